@@ -0,0 +1,354 @@
+//! Timers that follow the wall clock, rather than the monotonic clock.
+//!
+//! On Linux, a timerfd on CLOCK_REALTIME wakes the main loop both when the timer is due and
+//! when the system clock is changed, so schedules are recomputed immediately. Elsewhere, the
+//! wall clock is rechecked at least once a minute.
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Instant;
+use crate::{CbHandle, IODirection, IOEvent, MainLoopError, MultiIOAble};
+
+/// When a wall clock timer should fire.
+pub trait Schedule {
+    /// The first time strictly after `t` that the timer should fire, or None if never again.
+    fn next_after(&self, t: SystemTime) -> Option<SystemTime>;
+
+    /// The first time to fire, given the current time.
+    fn first(&self, now: SystemTime) -> Option<SystemTime> { self.next_after(now) }
+}
+
+/// Fires once. If the time has already passed, it fires as soon as possible.
+impl Schedule for SystemTime {
+    fn next_after(&self, t: SystemTime) -> Option<SystemTime> { if *self > t { Some(*self) } else { None } }
+    fn first(&self, _: SystemTime) -> Option<SystemTime> { Some(*self) }
+}
+
+fn to_time_t(t: SystemTime) -> libc::time_t {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as libc::time_t,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as libc::time_t),
+    }
+}
+
+fn from_time_t(t: libc::time_t) -> SystemTime {
+    if t >= 0 { UNIX_EPOCH + Duration::from_secs(t as u64) } else { UNIX_EPOCH - Duration::from_secs((t as u64).wrapping_neg()) }
+}
+
+fn local_tm(t: libc::time_t) -> libc::tm {
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&t, &mut tm);
+        tm
+    }
+}
+
+// Normalizes out-of-range fields (e g the 32nd day of a month) and fills in the weekday.
+fn normalize(tm: &mut libc::tm) -> libc::time_t {
+    tm.tm_isdst = -1;
+    unsafe { libc::mktime(tm) }
+}
+
+// Like normalize, but when the clocks go back and the local time occurs twice, picks the
+// occurrence after `t` if mktime picked the one before.
+fn normalize_after(tm: &mut libc::tm, t: libc::time_t) -> libc::time_t {
+    let orig = *tm;
+    let r = normalize(tm);
+    if r > t { return r }
+    // The second occurrence is in standard time
+    let mut later = orig;
+    later.tm_isdst = 0;
+    let r2 = unsafe { libc::mktime(&mut later) };
+    if r2 > t { *tm = later; return r2 }
+    r
+}
+
+/// Fires every day at a specific local time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Daily {
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+impl Daily {
+    /// Panics if the time is out of range.
+    pub fn at(hour: u8, minute: u8, second: u8) -> Self {
+        assert!(hour < 24 && minute < 60 && second < 60, "Invalid time of day");
+        Daily { hour: hour, minute: minute, second: second }
+    }
+}
+
+impl Schedule for Daily {
+    fn next_after(&self, t: SystemTime) -> Option<SystemTime> {
+        let tt = to_time_t(t);
+        let mut tm = local_tm(tt);
+        tm.tm_hour = self.hour as libc::c_int;
+        tm.tm_min = self.minute as libc::c_int;
+        tm.tm_sec = self.second as libc::c_int;
+        let mut r = normalize(&mut tm);
+        if from_time_t(r) <= t {
+            tm.tm_mday += 1;
+            tm.tm_hour = self.hour as libc::c_int;
+            tm.tm_min = self.minute as libc::c_int;
+            tm.tm_sec = self.second as libc::c_int;
+            r = normalize(&mut tm);
+        }
+        Some(from_time_t(r))
+    }
+}
+
+/// A cron-style schedule in local time, e g "0 3 * * *" for every day at 03:00.
+///
+/// The five fields are minute, hour, day of month, month and day of week (0 or 7 is Sunday).
+/// Each field is `*`, a number, a range `a-b`, or a comma separated list of those,
+/// optionally followed by a step, e g `*/15` or `1-5/2`. As in cron, if both day of month
+/// and day of week are restricted, either one matching is enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cron {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u32,
+    weekdays: u32,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(s: &str, min: u32, max: u32) -> Result<(u64, bool), MainLoopError> {
+    let err = || MainLoopError::Other(format!("Invalid cron field '{}'", s).into());
+    let num = |x: &str| x.parse::<u32>().ok().filter(|&n| n >= min && n <= max).ok_or_else(err);
+    let mut bits = 0u64;
+    for part in s.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => (&part[..idx], part[idx+1..].parse::<u32>().ok().filter(|&n| n > 0).ok_or_else(err)?),
+            None => (part, 1),
+        };
+        let (a, b) = if range == "*" { (min, max) } else if let Some(idx) = range.find('-') {
+            (num(&range[..idx])?, num(&range[idx+1..])?)
+        } else {
+            let a = num(range)?;
+            // "5/10" means from 5 to the end
+            (a, if step > 1 { max } else { a })
+        };
+        if a > b { return Err(err()) }
+        for n in (a..=b).step_by(step as usize) { bits |= 1 << n; }
+    }
+    Ok((bits, s == "*"))
+}
+
+impl Cron {
+    pub fn new(expr: &str) -> Result<Self, MainLoopError> {
+        let fields: Vec<_> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(MainLoopError::Other(format!("Expected five fields in cron expression '{}'", expr).into()));
+        }
+        let (minutes, _) = parse_field(fields[0], 0, 59)?;
+        let (hours, _) = parse_field(fields[1], 0, 23)?;
+        let (days, any_day) = parse_field(fields[2], 1, 31)?;
+        let (months, _) = parse_field(fields[3], 1, 12)?;
+        let (mut weekdays, any_weekday) = parse_field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 { weekdays |= 1; }
+        Ok(Cron {
+            minutes: minutes,
+            hours: hours as u32,
+            days: days as u32,
+            months: months as u32,
+            weekdays: weekdays as u32,
+            any_day: any_day,
+            any_weekday: any_weekday,
+        })
+    }
+
+    fn day_matches(&self, tm: &libc::tm) -> bool {
+        let d = self.days & (1 << tm.tm_mday) != 0;
+        let w = self.weekdays & (1 << tm.tm_wday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => d,
+            (true, false) => w,
+            (false, false) => d || w,
+        }
+    }
+}
+
+impl Schedule for Cron {
+    fn next_after(&self, t: SystemTime) -> Option<SystemTime> {
+        let tt = to_time_t(t);
+        let mut tm = local_tm(tt);
+        tm.tm_sec = 0;
+        tm.tm_min += 1;
+        let mut r = normalize_after(&mut tm, tt);
+        // Enough to cover several years, in case of e g February 30th
+        for _ in 0..10000 {
+            if self.months & (1 << (tm.tm_mon + 1)) == 0 {
+                tm.tm_mon += 1; tm.tm_mday = 1; tm.tm_hour = 0; tm.tm_min = 0;
+            } else if !self.day_matches(&tm) {
+                tm.tm_mday += 1; tm.tm_hour = 0; tm.tm_min = 0;
+            } else if self.hours & (1 << tm.tm_hour) == 0 {
+                tm.tm_hour += 1; tm.tm_min = 0;
+            } else if self.minutes & (1 << tm.tm_min) == 0 || r <= tt {
+                tm.tm_min += 1;
+            } else {
+                return Some(from_time_t(r));
+            }
+            r = normalize_after(&mut tm, tt);
+        }
+        None
+    }
+}
+
+/// Rechecks the wall clock at least this often, where the OS cannot tell us when it changes.
+#[cfg(not(target_os = "linux"))]
+const RECHECK: Duration = Duration::from_secs(60);
+
+pub (crate) struct WallTimer<S: Schedule, F: FnMut(SystemTime) -> bool> {
+    schedule: S,
+    f: F,
+    next: Option<SystemTime>,
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::OwnedFd,
+}
+
+impl<S: Schedule, F: FnMut(SystemTime) -> bool> WallTimer<S, F> {
+    pub (crate) fn new(schedule: S, f: F) -> Result<Self, MainLoopError> {
+        #[cfg(target_os = "linux")]
+        let fd = unsafe {
+            use std::os::unix::io::FromRawFd;
+            let fd = libc::timerfd_create(libc::CLOCK_REALTIME, libc::TFD_CLOEXEC | libc::TFD_NONBLOCK);
+            if fd < 0 { return Err(MainLoopError::Other(io::Error::last_os_error().into())) }
+            std::os::unix::io::OwnedFd::from_raw_fd(fd)
+        };
+        let next = schedule.first(SystemTime::now());
+        let t = WallTimer {
+            schedule: schedule,
+            f: f,
+            next: next,
+            #[cfg(target_os = "linux")]
+            fd: fd,
+        };
+        t.arm().map_err(|e| MainLoopError::Other(e.into()))?;
+        Ok(t)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn arm(&self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        let value = match self.next {
+            // Zero would disarm the timer, so times before 1970 fire right away
+            Some(n) => n.duration_since(UNIX_EPOCH).map(|d| libc::timespec {
+                tv_sec: d.as_secs() as libc::time_t, tv_nsec: d.subsec_nanos() as libc::c_long
+            }).unwrap_or(libc::timespec { tv_sec: 0, tv_nsec: 1 }),
+            None => zero,
+        };
+        let spec = libc::itimerspec { it_interval: zero, it_value: value };
+        let flags = libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET;
+        let r = unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), flags, &spec, std::ptr::null_mut()) };
+        // Setting the timer fails with ECANCELED if the clock has changed since the last read;
+        // it's armed anyway, and the next read reports the change.
+        if r < 0 && io::Error::last_os_error().raw_os_error() != Some(libc::ECANCELED) {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn arm(&self) -> io::Result<()> { Ok(()) }
+
+    // Returns false if the timer fd has nothing to report.
+    #[cfg(target_os = "linux")]
+    fn read_fd(&self) -> bool {
+        use std::os::unix::io::AsRawFd;
+        let mut buf = 0u64;
+        let r = unsafe { libc::read(self.fd.as_raw_fd(), &mut buf as *mut _ as *mut libc::c_void, 8) };
+        // ECANCELED means the clock was changed
+        r >= 0 || io::Error::last_os_error().raw_os_error() == Some(libc::ECANCELED)
+    }
+}
+
+impl<S: Schedule, F: FnMut(SystemTime) -> bool> MultiIOAble for WallTimer<S, F> {
+    fn handles(&self) -> Vec<(CbHandle, IODirection)> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            vec!((CbHandle(self.fd.as_raw_fd()), IODirection::Read))
+        }
+        #[cfg(not(target_os = "linux"))]
+        vec!()
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        #[cfg(target_os = "linux")]
+        return None;
+        #[cfg(not(target_os = "linux"))]
+        self.next.map(|n| {
+            let left = n.duration_since(SystemTime::now()).unwrap_or_default();
+            Instant::now() + std::cmp::min(left, RECHECK)
+        })
+    }
+
+    fn on_ready(&mut self, _: &[(CbHandle, IOEvent)]) -> bool {
+        #[cfg(target_os = "linux")]
+        { if !self.read_fd() { return true } }
+        let next = match self.next { Some(n) => n, None => return false };
+        let now = SystemTime::now();
+        if now < next {
+            // The clock was changed, or we're just rechecking. If the clock went backwards,
+            // an earlier time might be due first.
+            if let Some(n) = self.schedule.first(now) {
+                if n < next { self.next = Some(n); }
+            }
+        } else {
+            if !(self.f)(next) { return false }
+            let after = std::cmp::max(now, next);
+            // A schedule returning a time that has passed would make the timer fire over and over
+            self.next = self.schedule.next_after(after).map(|n| std::cmp::max(n, after + Duration::from_secs(1)));
+            if self.next.is_none() { return false }
+        }
+        self.arm().is_ok()
+    }
+}
+
+#[test]
+fn cron_schedule() {
+    let c = Cron::new("*/15 3 * * 1-5").unwrap();
+    let mut t = SystemTime::now();
+    for _ in 0..10 {
+        let n = c.next_after(t).unwrap();
+        assert!(n > t);
+        let tm = local_tm(to_time_t(n));
+        assert_eq!(tm.tm_hour, 3);
+        assert_eq!(tm.tm_min % 15, 0);
+        assert_eq!(tm.tm_sec, 0);
+        assert!(tm.tm_wday >= 1 && tm.tm_wday <= 5);
+        t = n;
+    }
+    let d = Daily::at(12, 30, 5).next_after(t).unwrap();
+    assert!(d > t && d - Duration::from_secs(25 * 3600) < t);
+    let tm = local_tm(to_time_t(d));
+    assert_eq!((tm.tm_hour, tm.tm_min, tm.tm_sec), (12, 30, 5));
+
+    assert!(Cron::new("0 0 30 2 *").unwrap().next_after(t).is_none());
+    assert!(Cron::new("60 * * * *").is_err());
+    assert!(Cron::new("* * * *").is_err());
+}
+
+#[test]
+fn wall_clock_timer() {
+    use std::cell::Cell;
+    use crate::MainLoop;
+
+    for b in crate::BackendKind::available() {
+        let fired = Cell::new(0);
+        let mut ml = MainLoop::with_backend(b).unwrap();
+        let target = SystemTime::now() + Duration::from_millis(50);
+        ml.call_at_time(SystemTime::now() - Duration::from_secs(10), || fired.set(fired.get() + 1)).unwrap();
+        ml.call_at_time(target, move || {
+            assert!(SystemTime::now() >= target);
+            crate::terminate();
+        }).unwrap();
+        ml.run();
+        assert_eq!(fired.get(), 1);
+    }
+}
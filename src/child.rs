@@ -0,0 +1,116 @@
+//! Watching child processes for exit.
+//!
+//! On Linux, the main loop waits for a pidfd to become readable. Elsewhere, or where
+//! pidfd_open is missing or not permitted, the child is checked every time the process
+//! receives SIGCHLD. The glib backend uses GLib's own child watch instead.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use crate::signal::{self, Registration};
+use crate::{CbHandle, IOAble, IODirection, IOEvent, MainLoopError};
+
+enum Waiter {
+    #[cfg(target_os = "linux")]
+    Pidfd(std::fs::File),
+    Sigchld(Registration, UnixStream),
+}
+
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: libc::pid_t) -> io::Result<std::fs::File> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()) }
+    Ok(unsafe { std::fs::File::from_raw_fd(fd as libc::c_int) })
+}
+
+pub (crate) fn check_pid(pid: libc::pid_t) -> Result<(), MainLoopError> {
+    if pid <= 0 { return Err(MainLoopError::Other("Invalid process id".into())) }
+    Ok(())
+}
+
+pub (crate) struct ChildWatch<F: FnOnce(ExitStatus)> {
+    pid: libc::pid_t,
+    waiter: Waiter,
+    f: Option<F>,
+}
+
+impl<F: FnOnce(ExitStatus)> ChildWatch<F> {
+    pub (crate) fn new(pid: libc::pid_t, f: F) -> Result<Self, MainLoopError> {
+        check_pid(pid)?;
+        #[cfg(target_os = "linux")]
+        {
+            match pidfd_open(pid) {
+                Ok(fd) => return Ok(ChildWatch { pid: pid, waiter: Waiter::Pidfd(fd), f: Some(f) }),
+                // Old kernels, or containers where seccomp blocks pidfd_open
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) || e.raw_os_error() == Some(libc::EPERM) => {},
+                Err(e) => return Err(MainLoopError::Other(e.into())),
+            }
+        }
+        Self::with_sigchld(pid, f)
+    }
+
+    fn with_sigchld(pid: libc::pid_t, f: F) -> Result<Self, MainLoopError> {
+        let (reg, rx) = Registration::new(libc::SIGCHLD).map_err(|e| MainLoopError::Other(e.into()))?;
+        // The child might already have exited, so check once when the main loop starts watching
+        reg.notify();
+        Ok(ChildWatch { pid: pid, waiter: Waiter::Sigchld(reg, rx), f: Some(f) })
+    }
+}
+
+impl<F: FnOnce(ExitStatus)> IOAble for ChildWatch<F> {
+    fn handle(&self) -> CbHandle {
+        match &self.waiter {
+            #[cfg(target_os = "linux")]
+            Waiter::Pidfd(fd) => CbHandle(fd.as_raw_fd()),
+            Waiter::Sigchld(_, rx) => CbHandle(rx.as_raw_fd()),
+        }
+    }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: IOEvent) -> bool {
+        if let Waiter::Sigchld(_, rx) = &self.waiter {
+            if signal::drain(rx).is_err() { return false }
+        }
+        let mut status = 0;
+        let r = unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) };
+        if r == 0 { return true }
+        if r == self.pid {
+            if let Some(f) = self.f.take() { f(ExitStatus::from_raw(status)) }
+        }
+        // On errors, the child was not ours, or somebody else has waited for it already
+        false
+    }
+}
+
+// The children are reaped by the main loop
+#[allow(clippy::zombie_processes)]
+#[test]
+fn child_watch() {
+    use std::cell::Cell;
+    use crate::MainLoop;
+
+    for b in crate::BackendKind::available() {
+        let status = Cell::new(None);
+        let mut ml = MainLoop::with_backend(b).unwrap();
+        let child = std::process::Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        ml.call_child_watch(child.id() as libc::pid_t, |s| {
+            status.set(Some(s));
+            crate::terminate();
+        }).unwrap();
+        ml.run();
+        drop(ml);
+        assert_eq!(status.get().unwrap().code(), Some(3));
+
+        // And the same with the SIGCHLD fallback, for a child that has not exited yet
+        let mut ml = MainLoop::with_backend(b).unwrap();
+        let child = std::process::Command::new("sh").args(["-c", "sleep 0.05; exit 4"]).spawn().unwrap();
+        ml.call_io(ChildWatch::with_sigchld(child.id() as libc::pid_t, |s| {
+            status.set(Some(s));
+            crate::terminate();
+        }).unwrap()).unwrap();
+        ml.run();
+        drop(ml);
+        assert_eq!(status.get().unwrap().code(), Some(4));
+    }
+}
@@ -0,0 +1,355 @@
+use crate::{CbKind, CbId, MainLoopError, IODirection, IOMode};
+use crate::mainloop::SendFnOnce;
+use crate::epoll_set::{EpollSet, EventFdSender, cvt, dir_to_epoll, epoll_to_event, duration_to_timespec, instant_to_monotonic};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+
+// Epoll data for the eventfd, CbIds are never this high.
+const EVENTFD_DATA: u64 = u64::MAX;
+// Epoll data for IO fds is the fd with this bit set. Timers and MultiIO use their CbId.
+const FD_DATA: u64 = 1 << 63;
+
+enum Source {
+    Asap,
+    Timer(OwnedFd),
+    // The fd, the epoll events this callback waits for, and its IOMode
+    IO(RawFd, u32, IOMode),
+    Multi(EpollSet),
+}
+
+struct Entry<'a> {
+    source: Source,
+    // False for one-shot IO callbacks, between a call and set_io_direction
+    armed: bool,
+    kind: Option<CbKind<'a>>,
+}
+
+pub struct Backend<'a> {
+    epoll: OwnedFd,
+    eventfd: Arc<OwnedFd>,
+    recv: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    cb_map: RefCell<HashMap<CbId, Entry<'a>>>,
+    // An fd can only be added to epoll once, so IO callbacks on the same fd share a registration
+    fds: RefCell<HashMap<RawFd, Vec<CbId>>>,
+    asap: RefCell<VecDeque<CbId>>,
+}
+
+impl<'a> Backend<'a> {
+    pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
+        let epoll = unsafe { OwnedFd::from_raw_fd(cvt(libc::epoll_create1(libc::EPOLL_CLOEXEC))?) };
+        let eventfd = unsafe { OwnedFd::from_raw_fd(cvt(libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK))?) };
+        let mut ev = libc::epoll_event { events: libc::EPOLLIN as u32, u64: EVENTFD_DATA };
+        cvt(unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, eventfd.as_raw_fd(), &mut ev) })?;
+
+        let (tx, rx) = channel();
+        let eventfd = Arc::new(eventfd);
+        let be = Backend {
+            epoll: epoll,
+            eventfd: eventfd.clone(),
+            recv: rx,
+            cb_map: Default::default(),
+            fds: Default::default(),
+            asap: Default::default(),
+        };
+        Ok((be, Box::new(EventFdSender::new(eventfd, tx))))
+    }
+
+    fn epoll_add(&self, fd: RawFd, events: u32, data: u64) -> Result<(), MainLoopError> {
+        let mut ev = libc::epoll_event { events: events, u64: data };
+        cvt(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut ev) })?;
+        Ok(())
+    }
+
+    fn epoll_del(&self, fd: RawFd) {
+        // The fd might already be closed by its owner, so ignore errors
+        unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+    }
+
+    // Registers the combined interest of the IO callbacks on the fd, or removes the fd if there are none.
+    fn update_fd(&self, fd: RawFd) -> Result<(), MainLoopError> {
+        let (mut events, mut edge) = (0, true);
+        {
+            let fds = self.fds.borrow();
+            let ids = match fds.get(&fd) {
+                Some(ids) => ids,
+                None => { self.epoll_del(fd); return Ok(()) },
+            };
+            let map = self.cb_map.borrow();
+            for id in ids {
+                if let Some(Entry { source: Source::IO(_, ev, mode), armed, .. }) = map.get(id) {
+                    if *armed { events |= ev; }
+                    // Edge triggering applies to the whole registration, so it is only used
+                    // if all callbacks want it. Otherwise they all get level triggering.
+                    edge &= *mode == IOMode::Edge;
+                }
+            }
+        }
+        if edge { events |= libc::EPOLLET as u32 }
+        let mut ev = libc::epoll_event { events: events, u64: FD_DATA | fd as u64 };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_MOD, fd, &mut ev) } == 0 { return Ok(()) }
+        let e = io::Error::last_os_error();
+        // The fd is new, or it was closed and reopened, which removes it from epoll
+        if e.raw_os_error() == Some(libc::ENOENT) { return self.epoll_add(fd, events, FD_DATA | fd as u64) }
+        Err(MainLoopError::Other(e.into()))
+    }
+
+    // Calls the IO callbacks on the fd that wait for any of the events.
+    fn dispatch_fd(&self, fd: RawFd, evs: u32) {
+        let ids = match self.fds.borrow().get(&fd) {
+            Some(ids) => ids.clone(),
+            None => return,
+        };
+        for cbid in ids {
+            // Hangups and errors are reported to everyone, like poll does
+            let ev = match self.cb_map.borrow().get(&cbid) {
+                Some(Entry { source: Source::IO(_, events, _), armed: true, .. }) =>
+                    evs & (events | (libc::EPOLLERR | libc::EPOLLHUP) as u32),
+                _ => continue,
+            };
+            if ev == 0 { continue; }
+            self.dispatch(cbid, |kind| kind.call_mut(Some(epoll_to_event(ev))));
+        }
+    }
+
+    fn dispatch<F: FnOnce(&mut CbKind<'a>) -> bool>(&self, cbid: CbId, call: F) {
+        let kind = {
+            let mut map = self.cb_map.borrow_mut();
+            let entry = match map.get_mut(&cbid) {
+                Some(entry) => entry,
+                None => return,
+            };
+            if let Source::Timer(fd) = &entry.source {
+                // Reset the expiration counter
+                let mut buf = 0u64;
+                unsafe { libc::read(fd.as_raw_fd(), &mut buf as *mut _ as *mut libc::c_void, 8) };
+            }
+            entry.kind.take()
+        };
+        if let Some(mut kind) = kind {
+            if call(&mut kind) {
+                let (mut disarmed, mut failed) = (None, None);
+                if let Some(entry) = self.cb_map.borrow_mut().get_mut(&cbid) {
+                    match &mut entry.source {
+                        Source::Multi(set) => failed = set.update(&kind.multi_handles().unwrap(), kind.deadline()).err(),
+                        Source::IO(fd, _, IOMode::OneShot) => {
+                            entry.armed = false;
+                            disarmed = Some(*fd);
+                        },
+                        _ => {},
+                    }
+                    entry.kind = Some(kind);
+                }
+                if let Some(fd) = disarmed {
+                    if let Err(e) = self.update_fd(fd) { crate::mainloop::report_error(e) }
+                }
+                if let Some(e) = failed {
+                    // E g a handle that is closed, or a regular file, which epoll cannot watch
+                    crate::mainloop::report_error(e);
+                    self.remove(cbid);
+                }
+                return;
+            }
+            self.remove(cbid);
+            kind.post_call_mut();
+        } else {
+            // A previous call panicked
+            self.remove(cbid);
+        }
+    }
+
+    fn remove(&self, cbid: CbId) -> Option<CbKind<'a>> {
+        let entry = self.cb_map.borrow_mut().remove(&cbid)?;
+        match &entry.source {
+            Source::Asap => {},
+            Source::Timer(fd) => self.epoll_del(fd.as_raw_fd()),
+            Source::Multi(set) => self.epoll_del(set.as_raw_fd()),
+            Source::IO(fd, _, _) => {
+                {
+                    let mut fds = self.fds.borrow_mut();
+                    let ids = fds.get_mut(fd).unwrap();
+                    ids.retain(|x| *x != cbid);
+                    if ids.is_empty() { fds.remove(fd); }
+                }
+                // The fd might already be closed by its owner, so ignore errors
+                let _ = self.update_fd(*fd);
+            },
+        }
+        entry.kind
+    }
+
+    fn run_thread_calls(&self) {
+        let mut buf = 0u64;
+        unsafe { libc::read(self.eventfd.as_raw_fd(), &mut buf as *mut _ as *mut libc::c_void, 8) };
+        while let Ok(cb) = self.recv.try_recv() {
+            cb();
+        }
+    }
+}
+
+impl<'a> crate::mainloop::Backend<'a> for Backend<'a> {
+    fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+        let source = if let Some((handle, direction)) = cb.handle() {
+            let fd = handle.0;
+            let mode = cb.io_mode().unwrap_or(IOMode::Level);
+            self.cb_map.borrow_mut().insert(cbid, Entry { source: Source::IO(fd, dir_to_epoll(direction), mode), armed: true, kind: Some(cb) });
+            self.fds.borrow_mut().entry(fd).or_default().push(cbid);
+            if let Err(e) = self.update_fd(fd) {
+                self.remove(cbid);
+                return Err(e);
+            }
+            return Ok(());
+        } else if let Some(handles) = cb.multi_handles() {
+            let mut set = EpollSet::new()?;
+            set.update(&handles, cb.deadline())?;
+            self.epoll_add(set.as_raw_fd(), libc::EPOLLIN as u32, cbid.0)?;
+            Source::Multi(set)
+        } else if let Some(d) = cb.deadline() {
+            let fd = cvt(unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC | libc::TFD_NONBLOCK) })?;
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // Absolute, so intervals are counted from the deadline rather than from now
+            let value = instant_to_monotonic(d);
+            let interval = cb.period().map(duration_to_timespec).unwrap_or(libc::timespec { tv_sec: 0, tv_nsec: 0 });
+            let spec = libc::itimerspec { it_interval: interval, it_value: value };
+            cvt(unsafe { libc::timerfd_settime(fd.as_raw_fd(), libc::TFD_TIMER_ABSTIME, &spec, std::ptr::null_mut()) })?;
+            self.epoll_add(fd.as_raw_fd(), libc::EPOLLIN as u32, cbid.0)?;
+            Source::Timer(fd)
+        } else {
+            self.asap.borrow_mut().push_back(cbid);
+            Source::Asap
+        };
+        self.cb_map.borrow_mut().insert(cbid, Entry { source: source, armed: true, kind: Some(cb) });
+        Ok(())
+    }
+
+    fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+        self.asap.borrow_mut().retain(|x| *x != cbid);
+        self.remove(cbid)
+    }
+
+    fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        let fd = match self.cb_map.borrow_mut().get_mut(&cbid) {
+            Some(entry) => match &mut entry.source {
+                Source::IO(fd, events, _) => {
+                    *events = dir_to_epoll(dir);
+                    // Also re-arms one-shot callbacks
+                    entry.armed = true;
+                    *fd
+                },
+                _ => return Err(crate::mainloop::not_an_io_callback(cbid)),
+            },
+            None => return Err(crate::mainloop::not_an_io_callback(cbid)),
+        };
+        self.update_fd(fd)
+    }
+
+    fn run_one(&self, wait: bool) -> bool {
+        let asap = self.asap.borrow_mut().pop_front();
+        let timeout = if wait && asap.is_none() { -1 } else { 0 };
+
+        let mut events: [libc::epoll_event; 32] = unsafe { std::mem::zeroed() };
+        let r = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as libc::c_int, timeout) };
+        let n = if r < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                crate::mainloop::terminate_with_error(MainLoopError::Other(e.into()));
+            }
+            0
+        } else { r as usize };
+
+        for ev in &events[..n] {
+            let (data, evs) = (ev.u64, ev.events);
+            if data == EVENTFD_DATA {
+                self.run_thread_calls();
+                continue;
+            }
+            if data & FD_DATA != 0 {
+                self.dispatch_fd((data & !FD_DATA) as RawFd, evs);
+                continue;
+            }
+            let cbid = CbId(data);
+            let ready = match self.cb_map.borrow().get(&cbid) {
+                Some(Entry { source: Source::Multi(set), .. }) => match set.ready() {
+                    Some(ready) => Some(ready),
+                    None => continue,
+                },
+                _ => None,
+            };
+            if let Some(ready) = ready {
+                self.dispatch(cbid, |kind| kind.call_multi(&ready));
+            } else {
+                self.dispatch(cbid, |kind| kind.call_mut(None));
+            }
+        }
+
+        if let Some(cbid) = asap {
+            self.dispatch(cbid, |kind| kind.call_mut(None));
+            return true;
+        }
+        n > 0
+    }
+}
+
+#[test]
+fn read_write_socketpair() {
+    use std::os::unix::net::UnixStream;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use crate::{IOAble, IOEvent, CbHandle};
+    use crate::mainloop::Backend as _;
+
+    struct Echo(UnixStream, Vec<u8>);
+    impl IOAble for Echo {
+        fn handle(&self) -> CbHandle { CbHandle(self.0.as_raw_fd()) }
+        fn direction(&self) -> IODirection { IODirection::Both }
+        fn on_rw(&mut self, ev: IOEvent) -> bool {
+            if ev.writable && !self.1.is_empty() {
+                let n = self.0.write(&self.1).unwrap();
+                self.1.drain(..n);
+            }
+            !self.1.is_empty()
+        }
+    }
+
+    let (a, mut b) = UnixStream::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    let (be, _) = Backend::new().unwrap();
+    be.push(CbId(1), CbKind::io(Echo(a, b"Ping".to_vec()))).unwrap();
+    be.push(CbId(2), CbKind::after(|| {}, Duration::from_millis(10))).unwrap();
+    while !be.cb_map.borrow().is_empty() { be.run_one(true); }
+    let mut buf = [0u8; 4];
+    b.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"Ping");
+}
+
+#[test]
+fn edge_triggered() {
+    use std::os::unix::net::UnixStream;
+    use std::io::Write;
+    use std::cell::Cell;
+    use crate::{IOAble, IOEvent, CbHandle};
+    use crate::mainloop::Backend as _;
+
+    struct Edge<'b>(UnixStream, &'b Cell<u32>);
+    impl IOAble for Edge<'_> {
+        fn handle(&self) -> CbHandle { CbHandle(self.0.as_raw_fd()) }
+        fn direction(&self) -> IODirection { IODirection::Read }
+        fn mode(&self) -> IOMode { IOMode::Edge }
+        fn on_rw(&mut self, _: IOEvent) -> bool { self.1.set(self.1.get() + 1); true }
+    }
+
+    let calls = Cell::new(0);
+    let (a, mut b) = UnixStream::pair().unwrap();
+    let (be, _) = Backend::new().unwrap();
+    be.push(CbId(1), CbKind::io(Edge(a, &calls))).unwrap();
+    b.write_all(b"x").unwrap();
+    // Nothing is read, but there is only one edge
+    for _ in 0..3 { be.run_one(false); }
+    assert_eq!(calls.get(), 1);
+    b.write_all(b"y").unwrap();
+    for _ in 0..3 { be.run_one(false); }
+    assert_eq!(calls.get(), 2);
+}
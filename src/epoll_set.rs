@@ -0,0 +1,158 @@
+//! A set of file descriptors and a deadline, watched through a nested epoll fd.
+//!
+//! The Linux backends use this for MultiIO callbacks: the epoll fd becomes
+//! readable when any of the fds is ready, or when the deadline has passed.
+//! Also holds the helpers that both backends share.
+
+use crate::{CbHandle, MainLoopError, IODirection, IOEvent};
+use crate::mainloop::SendFnOnce;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Instant, Duration};
+
+// Epoll data for the timerfd, fds are never this high.
+const TIMER_DATA: u64 = u64::MAX;
+
+/// Sends closures to the main loop thread, and wakes it up through an eventfd.
+pub (crate) struct EventFdSender {
+    fd: Arc<OwnedFd>,
+    sender: Sender<Box<dyn FnOnce() + Send + 'static>>,
+}
+
+impl EventFdSender {
+    pub (crate) fn new(fd: Arc<OwnedFd>, sender: Sender<Box<dyn FnOnce() + Send + 'static>>) -> Self {
+        EventFdSender { fd: fd, sender: sender }
+    }
+}
+
+impl SendFnOnce for EventFdSender {
+    fn send(&self, f: Box<dyn FnOnce() + Send + 'static>) -> Result<(), MainLoopError> {
+        self.sender.send(f).map_err(|e| MainLoopError::Other(e.into()))?;
+        let one = 1u64;
+        let r = unsafe { libc::write(self.fd.as_raw_fd(), &one as *const _ as *const libc::c_void, 8) };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            // Counter overflow, the main loop is going to wake up anyway
+            if e.kind() != io::ErrorKind::WouldBlock { return Err(MainLoopError::Other(e.into())) }
+        }
+        Ok(())
+    }
+}
+
+pub (crate) fn cvt(r: libc::c_int) -> Result<libc::c_int, MainLoopError> {
+    if r < 0 { Err(MainLoopError::Other(io::Error::last_os_error().into())) } else { Ok(r) }
+}
+
+pub (crate) fn dir_to_epoll(d: IODirection) -> u32 {
+    let read = libc::EPOLLIN | libc::EPOLLPRI | libc::EPOLLRDHUP;
+    (match d {
+        IODirection::None => 0,
+        IODirection::Read => read,
+        IODirection::Write => libc::EPOLLOUT,
+        IODirection::Both => read | libc::EPOLLOUT,
+    }) as u32
+}
+
+pub (crate) fn epoll_to_event(events: u32) -> IOEvent {
+    let events = events as libc::c_int;
+    IOEvent {
+        readable: events & libc::EPOLLIN != 0,
+        writable: events & libc::EPOLLOUT != 0,
+        hangup: events & (libc::EPOLLHUP | libc::EPOLLRDHUP) != 0,
+        error: events & libc::EPOLLERR != 0,
+        priority: events & libc::EPOLLPRI != 0,
+    }
+}
+
+pub (crate) fn duration_to_timespec(d: Duration) -> libc::timespec {
+    // A zero timespec would disarm the timer, so make sure it fires.
+    let d = if d == Duration::from_secs(0) { Duration::from_nanos(1) } else { d };
+    libc::timespec { tv_sec: d.as_secs() as libc::time_t, tv_nsec: d.subsec_nanos() as libc::c_long }
+}
+
+/// The instant as an absolute CLOCK_MONOTONIC time, for TFD_TIMER_ABSTIME and IORING_TIMEOUT_ABS.
+///
+/// Instants that have passed become the current time, so the timer fires right away.
+pub (crate) fn instant_to_monotonic(i: Instant) -> libc::timespec {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    // Instant has no way to get at its clock value, so add the time left to the clock
+    let left = i.saturating_duration_since(Instant::now());
+    let nsec = now.tv_nsec as u64 + left.subsec_nanos() as u64;
+    libc::timespec {
+        tv_sec: now.tv_sec + left.as_secs() as libc::time_t + (nsec / 1_000_000_000) as libc::time_t,
+        tv_nsec: (nsec % 1_000_000_000) as libc::c_long,
+    }
+}
+
+pub (crate) struct EpollSet {
+    epoll: OwnedFd,
+    timer: OwnedFd,
+    // Registered fds and their epoll events
+    fds: HashMap<RawFd, u32>,
+}
+
+impl EpollSet {
+    pub fn new() -> Result<Self, MainLoopError> {
+        let epoll = unsafe { OwnedFd::from_raw_fd(cvt(libc::epoll_create1(libc::EPOLL_CLOEXEC))?) };
+        let timer = cvt(unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC | libc::TFD_NONBLOCK) })?;
+        let timer = unsafe { OwnedFd::from_raw_fd(timer) };
+        let mut ev = libc::epoll_event { events: libc::EPOLLIN as u32, u64: TIMER_DATA };
+        cvt(unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, timer.as_raw_fd(), &mut ev) })?;
+        Ok(EpollSet { epoll: epoll, timer: timer, fds: Default::default() })
+    }
+
+    /// Makes the set watch these handles and this deadline, instead of the previous ones.
+    pub fn update(&mut self, handles: &[(CbHandle, IODirection)], deadline: Option<Instant>) -> Result<(), MainLoopError> {
+        let mut fds: HashMap<RawFd, u32> = HashMap::new();
+        for (h, dir) in handles {
+            *fds.entry(h.0).or_insert(0) |= dir_to_epoll(*dir);
+        }
+        for fd in self.fds.keys().filter(|fd| !fds.contains_key(fd)) {
+            // The fd might already be closed by its owner, so ignore errors
+            unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL, *fd, std::ptr::null_mut()) };
+        }
+        for (fd, events) in &fds {
+            let op = match self.fds.get(fd) {
+                Some(old) if old == events => continue,
+                Some(_) => libc::EPOLL_CTL_MOD,
+                None => libc::EPOLL_CTL_ADD,
+            };
+            let mut ev = libc::epoll_event { events: *events, u64: *fd as u64 };
+            cvt(unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, *fd, &mut ev) })?;
+        }
+        self.fds = fds;
+
+        let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        let value = deadline.map(instant_to_monotonic).unwrap_or(zero);
+        let spec = libc::itimerspec { it_interval: zero, it_value: value };
+        cvt(unsafe { libc::timerfd_settime(self.timer.as_raw_fd(), libc::TFD_TIMER_ABSTIME, &spec, std::ptr::null_mut()) })?;
+        Ok(())
+    }
+
+    /// The ready handles, or None if no handle is ready and the deadline has not passed.
+    pub fn ready(&self) -> Option<Vec<(CbHandle, IOEvent)>> {
+        let mut events = vec!(libc::epoll_event { events: 0, u64: 0 }; self.fds.len() + 1);
+        let r = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as libc::c_int, 0) };
+        let mut expired = false;
+        let mut v = vec!();
+        for ev in &events[..std::cmp::max(r, 0) as usize] {
+            let (data, evs) = (ev.u64, ev.events);
+            if data == TIMER_DATA {
+                let mut buf = 0u64;
+                unsafe { libc::read(self.timer.as_raw_fd(), &mut buf as *mut _ as *mut libc::c_void, 8) };
+                expired = true;
+            } else {
+                v.push((CbHandle(data as RawFd), epoll_to_event(evs)));
+            }
+        }
+        if v.is_empty() && !expired { None } else { Some(v) }
+    }
+}
+
+impl AsRawFd for EpollSet {
+    fn as_raw_fd(&self) -> RawFd { self.epoll.as_raw_fd() }
+}
@@ -0,0 +1,283 @@
+//! Splitting incoming bytes into frames, such as lines or length-prefixed messages.
+
+use std::io::{self, Read};
+use crate::{CbHandle, IOAble, IODirection, IOEvent};
+
+/// Default maximum frame length for the decoders in this module.
+pub const DEFAULT_MAX_LENGTH: usize = 8 * 1024 * 1024;
+
+fn too_long(max: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Frame longer than {} bytes", max))
+}
+
+/// Decodes frames from a buffer of incoming bytes.
+pub trait Decoder {
+    type Item;
+
+    /// Removes one complete frame from the start of the buffer and returns it,
+    /// or returns None if more data is needed.
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Self::Item>>;
+
+    /// Called when the stream has ended, until it returns None.
+    ///
+    /// The default implementation returns an UnexpectedEof error if there is a partial frame left.
+    fn decode_eof(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Self::Item>> {
+        match self.decode(buf)? {
+            Some(x) => Ok(Some(x)),
+            None if buf.is_empty() => Ok(None),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended in the middle of a frame")),
+        }
+    }
+}
+
+/// Frames prefixed with their length, as a big endian unsigned integer of 1, 2, 4 or 8 bytes.
+///
+/// The length does not include the prefix itself.
+#[derive(Debug, Clone)]
+pub struct LengthDelimited {
+    prefix: usize,
+    max: usize,
+}
+
+impl LengthDelimited {
+    /// Creates a decoder with a four byte prefix.
+    pub fn new() -> Self { LengthDelimited { prefix: 4, max: DEFAULT_MAX_LENGTH } }
+
+    /// Panics if the prefix length is not 1, 2, 4 or 8.
+    pub fn with_prefix_length(mut self, prefix: usize) -> Self {
+        assert!([1, 2, 4, 8].contains(&prefix), "Invalid prefix length {}", prefix);
+        self.prefix = prefix;
+        self
+    }
+
+    pub fn with_max_length(mut self, max: usize) -> Self { self.max = max; self }
+}
+
+impl Default for LengthDelimited {
+    fn default() -> Self { Self::new() }
+}
+
+impl Decoder for LengthDelimited {
+    type Item = Vec<u8>;
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if buf.len() < self.prefix { return Ok(None) }
+        let len = buf[..self.prefix].iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        if len > self.max as u64 { return Err(too_long(self.max)) }
+        let end = self.prefix + len as usize;
+        if buf.len() < end { return Ok(None) }
+        let frame = buf[self.prefix..end].to_vec();
+        buf.drain(..end);
+        Ok(Some(frame))
+    }
+}
+
+/// Frames ending with a delimiter byte. The delimiter is not included in the frame.
+///
+/// At the end of the stream, remaining data is returned as a final frame.
+#[derive(Debug, Clone)]
+pub struct Delimited {
+    delim: u8,
+    max: usize,
+    // How far we've already searched for the delimiter
+    searched: usize,
+}
+
+impl Delimited {
+    pub fn new(delim: u8) -> Self { Delimited { delim: delim, max: DEFAULT_MAX_LENGTH, searched: 0 } }
+
+    pub fn with_max_length(mut self, max: usize) -> Self { self.max = max; self }
+}
+
+impl Decoder for Delimited {
+    type Item = Vec<u8>;
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        match buf[self.searched..].iter().position(|&b| b == self.delim) {
+            Some(pos) => {
+                let pos = self.searched + pos;
+                self.searched = 0;
+                if pos > self.max { return Err(too_long(self.max)) }
+                let mut frame: Vec<u8> = buf.drain(..pos + 1).collect();
+                frame.pop();
+                Ok(Some(frame))
+            }
+            None => {
+                self.searched = buf.len();
+                if buf.len() > self.max { return Err(too_long(self.max)) }
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if let Some(x) = self.decode(buf)? { return Ok(Some(x)) }
+        self.searched = 0;
+        if buf.is_empty() { Ok(None) } else { Ok(Some(std::mem::take(buf))) }
+    }
+}
+
+/// Newline terminated UTF-8 lines. The line ending ("\n" or "\r\n") is not included.
+///
+/// At the end of the stream, a final line without a line ending is returned too.
+#[derive(Debug, Clone)]
+pub struct Lines(Delimited);
+
+impl Lines {
+    pub fn new() -> Self { Lines(Delimited::new(b'\n')) }
+
+    pub fn with_max_length(self, max: usize) -> Self { Lines(self.0.with_max_length(max)) }
+
+    fn to_line(frame: Option<Vec<u8>>) -> io::Result<Option<String>> {
+        let mut frame = match frame { Some(f) => f, None => return Ok(None) };
+        if frame.last() == Some(&b'\r') { frame.pop(); }
+        String::from_utf8(frame).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl Default for Lines {
+    fn default() -> Self { Self::new() }
+}
+
+impl Decoder for Lines {
+    type Item = String;
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<String>> {
+        Self::to_line(self.0.decode(buf)?)
+    }
+    fn decode_eof(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<String>> {
+        Self::to_line(self.0.decode_eof(buf)?)
+    }
+}
+
+/// Reads from a non-blocking I/O object and calls the callback once per complete frame.
+///
+/// Partial input is buffered until the rest of the frame arrives. When the stream ends,
+/// the callback is called with Ok(None); on errors, it is called with the error.
+/// In both cases the reader is then removed from the main loop.
+pub struct FrameReader<IO, D: Decoder, F: FnMut(&mut IO, io::Result<Option<D::Item>>)> {
+    io: IO,
+    decoder: D,
+    f: F,
+    buf: Vec<u8>,
+}
+
+/// Reads lines of text, see `FrameReader` and `Lines`.
+pub type LineReader<IO, F> = FrameReader<IO, Lines, F>;
+
+impl<IO, D, F> FrameReader<IO, D, F>
+where IO: Read, D: Decoder, F: FnMut(&mut IO, io::Result<Option<D::Item>>)
+{
+    /// The I/O object must be in non-blocking mode.
+    pub fn new(io: IO, decoder: D, f: F) -> Self {
+        FrameReader { io: io, decoder: decoder, f: f, buf: vec!() }
+    }
+
+    fn fail(&mut self, e: io::Error) -> bool {
+        (self.f)(&mut self.io, Err(e));
+        false
+    }
+
+    fn on_event(&mut self) -> bool {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.io.read(&mut chunk) {
+                Ok(0) => {
+                    loop {
+                        match self.decoder.decode_eof(&mut self.buf) {
+                            Ok(Some(x)) => (self.f)(&mut self.io, Ok(Some(x))),
+                            Ok(None) => break,
+                            Err(e) => return self.fail(e),
+                        }
+                    }
+                    (self.f)(&mut self.io, Ok(None));
+                    return false;
+                }
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return self.fail(e),
+            }
+            loop {
+                match self.decoder.decode(&mut self.buf) {
+                    Ok(Some(x)) => (self.f)(&mut self.io, Ok(Some(x))),
+                    Ok(None) => break,
+                    Err(e) => return self.fail(e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<IO, D, F> IOAble for FrameReader<IO, D, F>
+where IO: Read + std::os::unix::io::AsRawFd,
+      D: Decoder,
+      F: FnMut(&mut IO, io::Result<Option<D::Item>>)
+{
+    fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_fd()) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: IOEvent) -> bool { self.on_event() }
+}
+
+#[cfg(windows)]
+impl<IO, D, F> IOAble for FrameReader<IO, D, F>
+where IO: Read + std::os::windows::io::AsRawSocket,
+      D: Decoder,
+      F: FnMut(&mut IO, io::Result<Option<D::Item>>)
+{
+    fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_socket()) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: IOEvent) -> bool { self.on_event() }
+}
+
+#[test]
+fn decoders() {
+    let mut buf = b"\x00\x03abc\x00\x05de".to_vec();
+    let mut d = LengthDelimited::new().with_prefix_length(2);
+    assert_eq!(d.decode(&mut buf).unwrap(), Some(b"abc".to_vec()));
+    assert_eq!(d.decode(&mut buf).unwrap(), None);
+    assert_eq!(d.decode_eof(&mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    let mut buf = b"\x00\x00\x01\x00".to_vec();
+    assert!(LengthDelimited::new().with_max_length(255).decode(&mut buf).is_err());
+
+    let mut buf = b"a,bc,d".to_vec();
+    let mut d = Delimited::new(b',');
+    assert_eq!(d.decode(&mut buf).unwrap(), Some(b"a".to_vec()));
+    assert_eq!(d.decode(&mut buf).unwrap(), Some(b"bc".to_vec()));
+    assert_eq!(d.decode(&mut buf).unwrap(), None);
+    assert_eq!(d.decode_eof(&mut buf).unwrap(), Some(b"d".to_vec()));
+    assert_eq!(d.decode_eof(&mut buf).unwrap(), None);
+
+    let mut buf = b"Hello\r\nWorld\n\xff\n".to_vec();
+    let mut d = Lines::new();
+    assert_eq!(d.decode(&mut buf).unwrap().unwrap(), "Hello");
+    assert_eq!(d.decode(&mut buf).unwrap().unwrap(), "World");
+    assert_eq!(d.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+#[cfg(unix)]
+#[test]
+fn line_reader() {
+    use std::os::unix::net::UnixStream;
+    use std::io::Write;
+    use crate::MainLoop;
+
+    let (a, mut b) = UnixStream::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    let mut lines = vec!();
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_io(LineReader::new(a, Lines::new(), |_: &mut UnixStream, r: io::Result<Option<String>>| {
+            match r.unwrap() {
+                Some(s) => lines.push(s),
+                None => crate::terminate(),
+            }
+        })).unwrap();
+        // A message spanning several reads
+        b.write_all(b"first\nsec").unwrap();
+        ml.call_after(std::time::Duration::from_millis(20), move || {
+            b.write_all(b"ond\nthird").unwrap();
+            drop(b);
+        }).unwrap();
+        ml.run();
+    }
+    assert_eq!(lines, vec!("first", "second", "third"));
+}
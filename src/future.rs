@@ -3,45 +3,238 @@
 use std::future::Future;
 use futures::task;
 use futures::stream::Stream;
-use futures::task::{Poll, Waker, Context, ArcWake};
+use futures::task::{Poll, Waker, Context, ArcWake, LocalSpawn, Spawn, SpawnError, LocalFutureObj, FutureObj};
 use std::pin::Pin;
 use std::mem;
 use std::sync::{Arc, Mutex};
-use crate::{MainLoopError, MainLoop, IODirection, CbHandle, IOAble};
+use crate::{MainLoopError, MainLoop, IODirection, IOEvent, CbHandle, CbId, IOAble};
 use std::collections::{HashMap, VecDeque};
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
+use std::thread::{self, ThreadId};
 
-use std::time::Instant;
+use std::time::{Instant, Duration};
+
+#[cfg(unix)]
+mod async_io;
+
+#[cfg(unix)]
+pub use self::async_io::Async;
+
+#[cfg(unix)]
+pub mod net;
+
+#[cfg(unix)]
+pub mod process;
+
+mod framed;
+
+pub use self::framed::Framed;
 
 /// Waits until a specific instant.
-pub struct Delay(Instant);
+///
+/// Only one timer is registered with the main loop at a time; it is re-armed
+/// if the deadline or the waker changes, and cancelled when the Delay is dropped.
+pub struct Delay {
+    deadline: Instant,
+    timer: Option<DelayTimer>,
+}
+
+struct DelayTimer {
+    id: CbId,
+    waker: Waker,
+    fired: Rc<Cell<bool>>,
+}
+
+impl DelayTimer {
+    fn cancel(self) {
+        // If there's no main loop, the timer is gone anyway
+        if !self.fired.get() { let _ = crate::cancel(self.id); }
+    }
+}
+
+impl Delay {
+    /// The instant this Delay waits for.
+    pub fn deadline(&self) -> Instant { self.deadline }
+
+    /// Changes the instant to wait for.
+    pub fn reset(&mut self, deadline: Instant) {
+        if deadline == self.deadline { return; }
+        self.deadline = deadline;
+        if let Some(t) = self.timer.take() { t.cancel() };
+    }
+}
 
 impl Future for Delay {
     type Output = Result<(), MainLoopError>;
-    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
         let n = Instant::now();
         // println!("Polled at {:?}", n);
-        if self.0 <= n { Poll::Ready(Ok(())) }
-        else {
-            let lw = ctx.waker().clone();
-            match crate::call_after(self.0 - n, move || { lw.wake() }) {
-                Ok(_) => Poll::Pending,
-                Err(e) => Poll::Ready(Err(e)),
-            }
+        if self.deadline <= n {
+            if let Some(t) = self.timer.take() { t.cancel() };
+            return Poll::Ready(Ok(()));
         }
+        if let Some(t) = &self.timer {
+            // Timers might fire slightly early, in which case we need to re-arm
+            if !t.fired.get() && t.waker.will_wake(ctx.waker()) { return Poll::Pending; }
+        }
+        if let Some(t) = self.timer.take() { t.cancel() };
+
+        let lw = ctx.waker().clone();
+        let fired = Rc::new(Cell::new(false));
+        let fired2 = fired.clone();
+        match crate::call_at(self.deadline, move || { fired2.set(true); lw.wake() }) {
+            Ok(id) => {
+                self.timer = Some(DelayTimer { id: id, waker: ctx.waker().clone(), fired: fired });
+                Poll::Pending
+            },
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if let Some(t) = self.timer.take() { t.cancel() };
     }
 }
 
 /// Waits until a specific instant.
 pub fn delay(i: Instant) -> Delay {
-    Delay(i)
+    Delay { deadline: i, timer: None }
+}
+
+/// Waits for a specific duration.
+pub fn sleep(d: Duration) -> Delay {
+    delay(Instant::now() + d)
+}
+
+/// Waits until a specific instant.
+pub fn sleep_until(i: Instant) -> Delay {
+    delay(i)
+}
+
+struct IntervalInternal {
+    period: Duration,
+    id: Cell<Option<CbId>>,
+    ticks: Cell<u64>,
+    waker: RefCell<Option<Waker>>,
+}
+
+/// Interval implements "futures::Stream", so it will output an item
+/// at regular intervals.
+///
+/// If the stream is not polled in time, missed ticks are coalesced into one item.
+pub struct Interval(Rc<IntervalInternal>);
+
+impl Stream for Interval {
+    type Item = Result<(), MainLoopError>;
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let s: &Rc<IntervalInternal> = &self.0;
+        if s.id.get().is_none() {
+            // Submit to the reactor
+            let weak = Rc::downgrade(s);
+            let r = crate::call_interval(s.period, move || {
+                let s = match weak.upgrade() { Some(s) => s, None => return false };
+                s.ticks.set(s.ticks.get() + 1);
+                if let Some(w) = s.waker.borrow_mut().take() { w.wake() };
+                true
+            });
+            match r {
+                Ok(id) => s.id.set(Some(id)),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+        if s.ticks.get() > 0 {
+            s.ticks.set(0);
+            Poll::Ready(Some(Ok(())))
+        } else {
+            *s.waker.borrow_mut() = Some(ctx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        if let Some(id) = self.0.id.get() { let _ = crate::cancel(id); }
+    }
+}
+
+/// Creates a new Interval, which outputs an item every period.
+///
+/// The first item is output one period after the Interval is first polled.
+pub fn interval(period: Duration) -> Interval {
+    Interval(Rc::new(IntervalInternal {
+        period: period,
+        id: Cell::new(None),
+        ticks: Cell::new(0),
+        waker: Default::default(),
+    }))
+}
+
+/// Error returned from a Timeout future.
+#[derive(Debug)]
+pub enum TimeoutError {
+    /// The time ran out before the future finished.
+    Elapsed,
+    /// The timer could not be registered, e g when not polled on a main loop.
+    Timer(MainLoopError),
+}
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TimeoutError::Elapsed => write!(f, "Timeout elapsed"),
+            TimeoutError::Timer(e) => write!(f, "Timeout could not register its timer: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TimeoutError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TimeoutError::Elapsed => None,
+            TimeoutError::Timer(e) => Some(e),
+        }
+    }
+}
+
+/// Future returned from the "timeout" function.
+pub struct Timeout<F> {
+    future: F,
+    delay: Delay,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, TimeoutError>;
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        // The future is pinned along with the Timeout: it is never moved out of it, and
+        // Timeout has no Drop impl. The Delay is Unpin.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(r) = future.poll(ctx) {
+            return Poll::Ready(Ok(r));
+        }
+        match Pin::new(&mut this.delay).poll(ctx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Err(TimeoutError::Elapsed)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(TimeoutError::Timer(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Runs a future, but gives up after a specific duration.
+///
+/// Resolves to Err(TimeoutError::Elapsed) if the future did not finish in time.
+pub fn timeout<F: Future>(d: Duration, f: F) -> Timeout<F> {
+    Timeout { future: f, delay: sleep(d) }
 }
 
 struct IoInternal {
     cb_handle: CbHandle,
     direction: IODirection,
-    queue: RefCell<VecDeque<Result<IODirection, std::io::Error>>>,
+    queue: RefCell<VecDeque<IOEvent>>,
     alive: Cell<bool>,
     started: Cell<bool>,
     waker: RefCell<Option<Waker>>,
@@ -54,7 +247,7 @@ pub struct Io(Rc<IoInternal>);
 impl IOAble for Io {
     fn handle(&self) -> CbHandle { self.0.cb_handle }
     fn direction(&self) -> IODirection { self.0.direction }
-    fn on_rw(&mut self, r: Result<IODirection, std::io::Error>) -> bool {
+    fn on_rw(&mut self, r: IOEvent) -> bool {
         self.0.queue.borrow_mut().push_back(r);
         let w = self.0.waker.borrow();
         if let Some(waker) = &*w { waker.wake_by_ref() };
@@ -63,14 +256,14 @@ impl IOAble for Io {
 }
 
 impl Stream for Io {
-    type Item = Result<IODirection, MainLoopError>;
+    type Item = Result<IOEvent, MainLoopError>;
     fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
-        let s: &IoInternal = &(*self).0;
+        let s: &IoInternal = &self.0;
         if !s.alive.get() { return Poll::Ready(None); }
 
         if !s.started.get() {
             // Submit to the reactor
-            let c: &Rc<IoInternal> = &(*self).0;
+            let c: &Rc<IoInternal> = &self.0;
             let c = Io(c.clone());
             if let Err(e) = crate::call_io(c) {
                 s.alive.set(false);
@@ -81,8 +274,7 @@ impl Stream for Io {
 
         let q = s.queue.borrow_mut().pop_front();
         if let Some(item) = q {
-            let item = item.map_err(|e| MainLoopError::Other(Box::new(e)));
-            Poll::Ready(Some(item))
+            Poll::Ready(Some(Ok(item)))
         } else {
             *s.waker.borrow_mut() = Some(ctx.waker().clone());
             Poll::Pending
@@ -92,7 +284,7 @@ impl Stream for Io {
 
 impl Drop for Io {
     fn drop(&mut self) {
-        let s: &IoInternal = &(*self).0;
+        let s: &IoInternal = &self.0;
         s.alive.set(false);
     }
 }
@@ -109,18 +301,131 @@ pub fn io(handle: CbHandle, dir: IODirection) -> Io {
     }))
 }
 
+/// Signals implements "futures::Stream", so it will output an item whenever
+/// the process receives the signal.
+#[cfg(unix)]
+pub struct Signals(Result<(crate::signal::Registration, Async<std::os::unix::net::UnixStream>), Option<MainLoopError>>);
+
+#[cfg(unix)]
+impl Stream for Signals {
+    type Item = Result<crate::Signal, MainLoopError>;
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let (reg, rx) = match &mut self.get_mut().0 {
+            Ok(x) => (&x.0, &mut x.1),
+            Err(e) => return Poll::Ready(e.take().map(Err)),
+        };
+        let r = rx.poll_with(IODirection::Read, ctx, |rx| match crate::signal::drain(rx)? {
+            0 => Err(std::io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        });
+        r.map(|r| Some(r.map(|n| crate::Signal { signum: reg.signum(), count: n }).map_err(|e| MainLoopError::Other(e.into()))))
+    }
+}
+
+/// Creates a new Signals stream, which outputs an item whenever the process receives the signal.
+///
+/// The signal handler is installed immediately, so signals arriving before the stream is
+/// first polled are not lost.
+#[cfg(unix)]
+pub fn signals(signum: libc::c_int) -> Signals {
+    Signals(crate::signal::Registration::new(signum)
+        .and_then(|(reg, rx)| Ok((reg, Async::new(rx)?)))
+        .map_err(|e| Some(MainLoopError::Other(e.into()))))
+}
+
+/// PathWatch implements "futures::Stream", so it will output an item whenever
+/// the watched path changes on disk.
+#[cfg(target_os = "linux")]
+pub struct PathWatch {
+    watcher: Result<Async<crate::inotify::Watcher>, Option<MainLoopError>>,
+    queue: VecDeque<crate::FsEvent>,
+}
+
+#[cfg(target_os = "linux")]
+impl Stream for PathWatch {
+    type Item = Result<crate::FsEvent, MainLoopError>;
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let s = self.get_mut();
+        let w = match &mut s.watcher {
+            Ok(w) => w,
+            Err(e) => return Poll::Ready(e.take().map(Err)),
+        };
+        if let Some(e) = s.queue.pop_front() { return Poll::Ready(Some(Ok(e))) }
+        let r = w.poll_with(IODirection::Read, ctx, |w| {
+            let events = w.read_events()?;
+            if events.is_empty() { Err(std::io::ErrorKind::WouldBlock.into()) } else { Ok(events) }
+        });
+        r.map(|r| match r {
+            Ok(events) => {
+                s.queue.extend(events);
+                s.queue.pop_front().map(Ok)
+            }
+            Err(e) => Some(Err(MainLoopError::Other(e.into()))),
+        })
+    }
+}
+
+/// Creates a new PathWatch stream, which outputs an item whenever the path changes on disk.
+///
+/// The path is watched from when this function is called, not from when the stream is first polled.
+#[cfg(target_os = "linux")]
+pub fn watch_path<P: AsRef<std::path::Path>>(path: P, mask: crate::WatchMask) -> PathWatch {
+    let w = crate::inotify::Watcher::new(path.as_ref(), mask).and_then(Async::new);
+    PathWatch {
+        watcher: w.map_err(|e| Some(MainLoopError::Other(e.into()))),
+        queue: Default::default(),
+    }
+}
+
 // And the executor stuff 
 
 type BoxFuture<'a> = Pin<Box<dyn Future<Output=()> + 'a>>;
 
 type RunQueue = Arc<Mutex<Vec<u64>>>;
 
-struct Task(u64, RunQueue);
+struct Task(u64, RunQueue, ThreadId);
 
 impl ArcWake for Task {
     fn wake_by_ref(x: &Arc<Self>) {
-        x.1.lock().unwrap().push(x.0);
+        let was_empty = {
+            let mut q = x.1.lock().unwrap();
+            let e = q.is_empty();
+            q.push(x.0);
+            e
+        };
         // println!("Waking up");
+        // If the run queue was not empty, the executor has not yet gone to sleep.
+        if was_empty && thread::current().id() != x.2 {
+            // The main loop might be gone already, in which case there's nothing to wake up
+            let _ = crate::call_thread(x.2, || {});
+        }
+    }
+}
+
+// The tasks are shared between the executor and its spawners.
+struct Tasks<'a> {
+    map: RefCell<HashMap<u64, BoxFuture<'a>>>,
+    next_task: Cell<u64>,
+    run_queue: RunQueue,
+}
+
+impl<'a> Tasks<'a> {
+    fn spawn<T: 'a, F: Future<Output=T> + 'a>(self: &Rc<Self>, f: F) -> JoinHandle<'a, T> {
+        let id = self.next_task.get();
+        let state = Rc::new(JoinState {
+            id: id,
+            run_queue: self.run_queue.clone(),
+            result: RefCell::new(None),
+            waker: RefCell::new(None),
+            finished: Cell::new(false),
+            aborted: Cell::new(false),
+            detached: Cell::new(false),
+        });
+        let x = Box::pin(Spawned(Box::pin(f), state.clone()));
+        self.map.borrow_mut().insert(id, x);
+        self.run_queue.lock().unwrap().push(id);
+        self.next_task.set(id + 1);
+        JoinHandle(state, Rc::downgrade(self))
     }
 }
 
@@ -131,14 +436,13 @@ impl ArcWake for Task {
 /// It contains a MainLoop inside, so you can spawn 'static callbacks too. 
 pub struct Executor<'a> {
     ml: MainLoop<'a>,
-    tasks: HashMap<u64, BoxFuture<'a>>,
-    next_task: u64,
-    run_queue: RunQueue,
+    tasks: Rc<Tasks<'a>>,
 }
 
 impl<'a> Executor<'a> {
     pub fn new() -> Result<Self, MainLoopError> {
-        Ok(Executor { ml: MainLoop::new()?, next_task: 1, run_queue: Default::default(), tasks: Default::default() })
+        let tasks = Tasks { map: Default::default(), next_task: Cell::new(1), run_queue: Default::default() };
+        Ok(Executor { ml: MainLoop::new()?, tasks: Rc::new(tasks) })
     }
 
     /// Runs until the main loop is terminated.
@@ -152,26 +456,23 @@ impl<'a> Executor<'a> {
     /// Returns false if the mainloop was terminated.
     pub fn run_one(&mut self, allow_wait: bool) -> bool {
         let run_queue: Vec<_> = {
-            let mut r = self.run_queue.lock().unwrap();
-            mem::replace(&mut *r, vec!())
+            let mut r = self.tasks.run_queue.lock().unwrap();
+            mem::take(&mut *r)
         };
-        if run_queue.len() == 0 {
+        if run_queue.is_empty() {
             return self.ml.run_one(allow_wait);
         }
         for id in run_queue {
-            let remove = {
-                let f = self.tasks.get_mut(&id);
-                if let Some(f) = f {
-                    let pinf = f.as_mut();
-                    let t = Task(id, self.run_queue.clone());
-                    let t = Arc::new(t);
-                    let waker = task::waker_ref(&t);
-                    let mut ctx = Context::from_waker(&waker);
-                    pinf.poll(&mut ctx) != Poll::Pending
-                } else { false }
-            };
-            if remove {
-                self.tasks.remove(&id);
+            // Take the future out of the map while polling, so it can spawn new tasks.
+            let f = self.tasks.map.borrow_mut().remove(&id);
+            if let Some(mut f) = f {
+                let t = Task(id, self.tasks.run_queue.clone(), thread::current().id());
+                let t = Arc::new(t);
+                let waker = task::waker_ref(&t);
+                let mut ctx = Context::from_waker(&waker);
+                if f.as_mut().poll(&mut ctx) == Poll::Pending {
+                    self.tasks.map.borrow_mut().insert(id, f);
+                }
             }
         }
         true
@@ -181,42 +482,194 @@ impl<'a> Executor<'a> {
     ///
     /// Returns None if the main loop is terminated, or the result of the future otherwise.
     pub fn block_on<R: 'a, F: Future<Output=R> + 'a>(&mut self, f: F) -> Option<R> {
-        use futures::future::{FutureExt, ready};
-        let res = Arc::new(RefCell::new(None));
-        let res2 = res.clone();
-        let f = f.then(move |r| { *res2.borrow_mut() = Some(r); ready(()) });
-        self.spawn(f);
+        let h = self.spawn(f);
         loop {
             if !self.run_one(true) { return None };
-            let x = res.borrow_mut().take();
+            let x = h.0.result.borrow_mut().take();
             if x.is_some() { return x; }
         }
     }
 
-    pub fn spawn<F: Future<Output=()> + 'a>(&mut self, f: F) {
-        let x = Box::pin(f);
-        self.tasks.insert(self.next_task, x);
-        self.run_queue.lock().unwrap().push(self.next_task);
-        self.next_task += 1;
+    /// Spawns a future on the executor.
+    ///
+    /// Dropping the returned JoinHandle aborts the future, unless you call `JoinHandle::detach`.
+    pub fn spawn<T: 'a, F: Future<Output=T> + 'a>(&mut self, f: F) -> JoinHandle<'a, T> {
+        self.tasks.spawn(f)
+    }
+
+    /// Returns a handle that can spawn futures on this executor, e g from within other futures.
+    pub fn spawner(&self) -> LocalSpawner<'a> {
+        LocalSpawner(Rc::downgrade(&self.tasks))
+    }
+}
+
+/// Spawns futures on an Executor.
+///
+/// Unlike the Executor itself, the spawner can be cloned and moved into spawned futures.
+/// It implements the `LocalSpawn` and `Spawn` traits from the futures crate.
+#[derive(Clone)]
+pub struct LocalSpawner<'a>(Weak<Tasks<'a>>);
+
+impl<'a> LocalSpawner<'a> {
+    /// Spawns a future on the executor.
+    ///
+    /// Fails if the executor has been dropped.
+    pub fn spawn<T: 'a, F: Future<Output=T> + 'a>(&self, f: F) -> Result<JoinHandle<'a, T>, SpawnError> {
+        let tasks = self.0.upgrade().ok_or_else(SpawnError::shutdown)?;
+        Ok(tasks.spawn(f))
+    }
+
+    fn status(&self) -> Result<(), SpawnError> {
+        if self.0.strong_count() > 0 { Ok(()) } else { Err(SpawnError::shutdown()) }
+    }
+}
+
+impl LocalSpawn for LocalSpawner<'_> {
+    fn spawn_local_obj(&self, future: LocalFutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.spawn(future).map(|h| h.detach())
+    }
+    fn status_local(&self) -> Result<(), SpawnError> { self.status() }
+}
+
+impl Spawn for LocalSpawner<'_> {
+    fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.spawn(future).map(|h| h.detach())
+    }
+    fn status(&self) -> Result<(), SpawnError> { LocalSpawner::status(self) }
+}
+
+struct JoinState<T> {
+    id: u64,
+    run_queue: RunQueue,
+    result: RefCell<Option<T>>,
+    waker: RefCell<Option<Waker>>,
+    finished: Cell<bool>,
+    aborted: Cell<bool>,
+    detached: Cell<bool>,
+}
+
+// The future that actually runs on the executor: stores the result
+// in the JoinState, or finishes early if aborted.
+struct Spawned<'a, T>(Pin<Box<dyn Future<Output=T> + 'a>>, Rc<JoinState<T>>);
+
+impl<T> Future for Spawned<'_, T> {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        if self.1.aborted.get() { return Poll::Ready(()); }
+        let r = match self.0.as_mut().poll(ctx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(r) => r,
+        };
+        let s = &self.1;
+        s.finished.set(true);
+        if !s.detached.get() { *s.result.borrow_mut() = Some(r); }
+        if let Some(w) = s.waker.borrow_mut().take() { w.wake() };
+        Poll::Ready(())
+    }
+}
+
+/// Handle to a spawned future.
+///
+/// Await the handle to get the output of the future.
+/// Dropping the handle aborts the future, unless it has been detached. The future is dropped
+/// right away, unless the handle is dropped from within that same future.
+#[must_use = "dropping a JoinHandle aborts the spawned future, use detach() to let it run"]
+pub struct JoinHandle<'a, T>(Rc<JoinState<T>>, Weak<Tasks<'a>>);
+
+impl<T> JoinHandle<'_, T> {
+    /// Returns true if the spawned future has completed.
+    pub fn is_finished(&self) -> bool { self.0.finished.get() }
+
+    /// Stops the spawned future and removes it from the executor.
+    ///
+    /// Does nothing if the future has already completed.
+    pub fn abort(self) {
+        // Dropping the handle does the actual work
+    }
+
+    /// Lets the spawned future run to completion without a handle.
+    ///
+    /// The output of the future is discarded.
+    pub fn detach(self) {
+        self.0.detached.set(true);
+        self.0.result.borrow_mut().take();
+    }
+}
+
+impl<T> Future for JoinHandle<'_, T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<T> {
+        if let Some(r) = self.0.result.borrow_mut().take() { return Poll::Ready(r); }
+        if self.0.finished.get() { panic!("JoinHandle polled after completion") }
+        *self.0.waker.borrow_mut() = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for JoinHandle<'_, T> {
+    fn drop(&mut self) {
+        let s = &self.0;
+        if s.detached.get() || s.finished.get() || s.aborted.get() { return; }
+        s.aborted.set(true);
+        // Drop the future right away, outside the borrow, as it might own other JoinHandles.
+        let f = self.1.upgrade().and_then(|tasks| tasks.map.borrow_mut().remove(&s.id));
+        if f.is_some() { return; }
+        // The future is being polled right now. Let the executor poll it once more, so it gets removed.
+        s.run_queue.lock().unwrap().push(s.id);
     }
 }
 
+#[cfg(unix)]
+#[test]
+fn signals_stream() {
+    use futures::stream::StreamExt;
+    let mut x = Executor::new().unwrap();
+    let r = x.block_on(async {
+        let mut s = signals(libc::SIGUSR1);
+        unsafe { libc::raise(libc::SIGUSR1); }
+        let r = s.next().await.unwrap().unwrap();
+        let mut invalid = signals(0);
+        assert!(invalid.next().await.unwrap().is_err());
+        assert!(invalid.next().await.is_none());
+        r
+    }).unwrap();
+    assert_eq!(r.signum, libc::SIGUSR1);
+    assert!(r.count >= 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watch_path_stream() {
+    use futures::stream::StreamExt;
+    use crate::{FsEvent, WatchMask};
+    let dir = std::env::temp_dir().join(format!("thin_main_loop_watch_stream_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let mut x = Executor::new().unwrap();
+    let d = dir.clone();
+    let r = x.block_on(async move {
+        let mut s = watch_path(&d, WatchMask::CLOSE_WRITE);
+        std::fs::write(d.join("config"), b"x").unwrap();
+        s.next().await.unwrap().unwrap()
+    }).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(r, FsEvent::ClosedWrite(dir.join("config")));
+}
+
 #[test]
 fn delay_test() {
-    use std::time::Duration;
     use futures::future::{FutureExt, ready};
 
     let mut x = Executor::new().unwrap();
     let n = Instant::now() + Duration::from_millis(200);
     let f = delay(n).then(|_| { println!("Terminating!"); crate::terminate(); ready(()) });
-    x.spawn(f);
+    x.spawn(f).detach();
     x.run();
     assert!(Instant::now() >= n);
 }
 
 #[test]
 fn async_fn_test() {
-    use std::time::Duration;
 
     async fn foo(n: Instant) {
         delay(n).await.unwrap();
@@ -230,7 +683,6 @@ fn async_fn_test() {
 
 #[test]
 fn async_fn_test_ref() {
-    use std::time::Duration;
 
     async fn takes_ref(s: &str) {
         delay(Instant::now() + Duration::from_millis(50)).await.unwrap();
@@ -259,3 +711,128 @@ fn async_fn_test_ref() {
     x.block_on(calls_takes_ref());
 
 }
+
+#[test]
+fn wake_from_thread() {
+    use futures::channel::oneshot;
+
+    let mut x = Executor::new().unwrap();
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        tx.send(5u32).unwrap();
+    });
+    assert_eq!(x.block_on(rx), Some(Ok(5)));
+}
+
+#[test]
+fn join_handle() {
+
+    let mut x = Executor::new().unwrap();
+    let h1 = x.spawn(async { delay(Instant::now() + Duration::from_millis(20)).await.unwrap(); 5 });
+    let h2 = x.spawn(async { delay(Instant::now() + Duration::from_secs(10)).await.unwrap(); panic!("Should have been aborted") });
+    let h3 = x.spawn(async { 7 });
+    assert_eq!(x.tasks.map.borrow().len(), 3);
+    h2.abort();
+    assert_eq!(x.block_on(async { h1.await + h3.await }), Some(12));
+    assert!(x.tasks.map.borrow().is_empty());
+}
+
+#[test]
+fn drop_join_handle() {
+    struct Guard(Rc<Cell<bool>>);
+    impl Drop for Guard {
+        fn drop(&mut self) { self.0.set(true) }
+    }
+
+    let dropped = Rc::new(Cell::new(false));
+    let mut x = Executor::new().unwrap();
+    let g = Guard(dropped.clone());
+    let h = x.spawn(async move {
+        let _g = g;
+        sleep(Duration::from_secs(10)).await.unwrap();
+    });
+    assert!(x.run_one(false));
+    assert_eq!(x.tasks.map.borrow().len(), 1);
+    assert!(!dropped.get());
+    // The future, and everything it owns, goes away with the handle
+    drop(h);
+    assert!(dropped.get());
+    assert!(x.tasks.map.borrow().is_empty());
+}
+
+#[test]
+fn spawner() {
+    use futures::task::LocalSpawnExt;
+
+    let mut x = Executor::new().unwrap();
+    let sp = x.spawner();
+    let r = x.block_on(async move {
+        let h = sp.spawn(async { 5 }).unwrap();
+        let (tx, rx) = futures::channel::oneshot::channel();
+        sp.spawn_local(async move { tx.send(7).unwrap(); }).unwrap();
+        h.await + rx.await.unwrap()
+    });
+    assert_eq!(r, Some(12));
+    let sp = x.spawner();
+    drop(x);
+    assert!(sp.spawn_local(async {}).is_err());
+}
+
+#[test]
+fn delay_single_timer() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(AtomicUsize);
+    impl ArcWake for Counter {
+        fn wake_by_ref(x: &Arc<Self>) { x.0.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    let mut ml = MainLoop::new().unwrap();
+    let c1 = Arc::new(Counter(AtomicUsize::new(0)));
+    let c2 = Arc::new(Counter(AtomicUsize::new(0)));
+    let w1 = task::waker(c1.clone());
+    let w2 = task::waker(c2.clone());
+    let n = Instant::now() + Duration::from_millis(50);
+    let mut d1 = Box::pin(delay(n));
+    let mut d2 = Box::pin(delay(n));
+    for _ in 0..100 {
+        assert!(d1.as_mut().poll(&mut Context::from_waker(&w1)).is_pending());
+    }
+    assert!(d2.as_mut().poll(&mut Context::from_waker(&w2)).is_pending());
+    // Dropping cancels the timer
+    drop(d2);
+    ml.call_after(Duration::from_millis(100), crate::terminate).unwrap();
+    ml.run();
+    assert_eq!(c1.0.load(Ordering::SeqCst), 1);
+    assert_eq!(c2.0.load(Ordering::SeqCst), 0);
+    assert!(d1.as_mut().poll(&mut Context::from_waker(&w1)).is_ready());
+}
+
+#[test]
+fn timeout_without_main_loop() {
+    let mut t = Box::pin(timeout(Duration::from_millis(10), futures::future::pending::<()>()));
+    let w = task::noop_waker();
+    let r = t.as_mut().poll(&mut Context::from_waker(&w));
+    assert!(matches!(r, Poll::Ready(Err(TimeoutError::Timer(MainLoopError::NoMainLoop)))));
+}
+
+#[test]
+fn timers_test() {
+    use futures::stream::StreamExt;
+
+    let mut x = Executor::new().unwrap();
+    let n = Instant::now();
+    let r = x.block_on(async move {
+        let mut i = interval(Duration::from_millis(20));
+        for _ in 0..3 { i.next().await.unwrap().unwrap(); }
+        assert!(Instant::now() >= n + Duration::from_millis(60));
+
+        sleep(Duration::from_millis(10)).await.unwrap();
+        let r1 = timeout(Duration::from_millis(50), async { 5 }).await;
+        let r2 = timeout(Duration::from_millis(20), sleep(Duration::from_secs(10))).await;
+        (r1.unwrap(), matches!(r2, Err(TimeoutError::Elapsed)))
+    });
+    assert_eq!(r, Some((5, true)));
+    assert!(Instant::now() < n + Duration::from_secs(5));
+}
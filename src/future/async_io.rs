@@ -0,0 +1,158 @@
+//! AsyncRead / AsyncWrite support for file descriptors.
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll, Waker};
+use crate::{CbHandle, CbId, IOAble, IODirection, IOEvent};
+
+#[derive(Default)]
+struct Readiness {
+    id: Cell<Option<CbId>>,
+    waker: RefCell<Option<Waker>>,
+}
+
+// Registered when an operation would block, and removed on the first readiness event.
+struct OneShot(CbHandle, IODirection, Rc<Readiness>);
+
+impl IOAble for OneShot {
+    fn handle(&self) -> CbHandle { self.0 }
+    fn direction(&self) -> IODirection { self.1 }
+    fn on_rw(&mut self, _: IOEvent) -> bool {
+        self.2.id.set(None);
+        if let Some(w) = self.2.waker.borrow_mut().take() { w.wake() };
+        false
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Wraps an I/O object, such as a TcpStream, UnixStream, pipe or tty, to make it usable
+/// from async code running on the Executor.
+///
+/// Implements AsyncRead and AsyncWrite if the inner object implements Read and Write.
+/// Reads and writes are retried when the file descriptor becomes ready.
+pub struct Async<T: AsRawFd> {
+    // The registrations are dropped first, which only queues their cancels; the fd is
+    // closed right after. The main loop runs queued cancels before it pushes new callbacks,
+    // so the old registrations are gone before one for a reused fd number is added.
+    reg: Registrations,
+    io: T,
+}
+
+#[derive(Default)]
+struct Registrations {
+    read: Rc<Readiness>,
+    write: Rc<Readiness>,
+}
+
+impl Drop for Registrations {
+    fn drop(&mut self) {
+        for r in &[&self.read, &self.write] {
+            if let Some(id) = r.id.take() { let _ = crate::cancel(id); }
+        }
+    }
+}
+
+impl<T: AsRawFd> Async<T> {
+    /// Wraps the I/O object, and puts it in non-blocking mode.
+    pub fn new(io: T) -> io::Result<Self> {
+        set_nonblocking(io.as_raw_fd())?;
+        Ok(Async { reg: Default::default(), io: io })
+    }
+
+    pub fn get_ref(&self) -> &T { &self.io }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.io }
+
+    pub fn into_inner(self) -> T { self.io }
+
+    // Makes sure the task is woken up when the I/O object is ready for the given direction.
+    fn register(&self, dir: IODirection, ctx: &mut Context) -> io::Result<()> {
+        let r = if dir == IODirection::Read { &self.reg.read } else { &self.reg.write };
+        *r.waker.borrow_mut() = Some(ctx.waker().clone());
+        if r.id.get().is_none() {
+            let oneshot = OneShot(CbHandle(self.io.as_raw_fd()), dir, r.clone());
+            let id = crate::call_io(oneshot)?;
+            r.id.set(Some(id));
+        }
+        Ok(())
+    }
+
+    /// Runs an operation on the I/O object, retrying when the object becomes ready
+    /// if the operation returns WouldBlock.
+    pub fn poll_with<R, F>(&mut self, dir: IODirection, ctx: &mut Context, mut f: F) -> Poll<io::Result<R>>
+    where F: FnMut(&mut T) -> io::Result<R> {
+        loop {
+            match f(&mut self.io) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => return Poll::Ready(r),
+            }
+            return match self.register(dir, ctx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            };
+        }
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for Async<T> {
+    fn as_raw_fd(&self) -> RawFd { self.io.as_raw_fd() }
+}
+
+impl<T: AsRawFd + Read + Unpin> AsyncRead for Async<T> {
+    fn poll_read(self: Pin<&mut Self>, ctx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_with(IODirection::Read, ctx, |io| io.read(buf))
+    }
+}
+
+impl<T: AsRawFd + Write + Unpin> AsyncWrite for Async<T> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_with(IODirection::Write, ctx, |io| io.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_with(IODirection::Write, ctx, |io| io.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_flush(ctx)
+    }
+}
+
+#[test]
+fn unix_stream() {
+    use std::os::unix::net::UnixStream;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use super::Executor;
+
+    let (a, b) = UnixStream::pair().unwrap();
+    let mut x = Executor::new().unwrap();
+    // Large enough to fill up the socket buffers, so the writer has to wait for the reader
+    let data: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+    let data2 = data.clone();
+    let writer = x.spawn(async move {
+        let mut a = Async::new(a).unwrap();
+        a.write_all(&data2).await.unwrap();
+    });
+    let r = x.block_on(async move {
+        let mut b = Async::new(b).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        b.read_exact(&mut buf).await.unwrap();
+        writer.await;
+        buf == data
+    });
+    assert_eq!(r, Some(true));
+}
@@ -0,0 +1,85 @@
+use std::io;
+use std::pin::Pin;
+use futures::io::AsyncRead;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use crate::framed::Decoder;
+
+/// A stream of frames, decoded from an AsyncRead such as `Async` or `net::TcpStream`.
+///
+/// The stream ends when the reader does. If the decoder or the reader fails, the error is
+/// yielded and then the stream ends.
+pub struct Framed<R, D> {
+    io: R,
+    decoder: D,
+    buf: Vec<u8>,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin, D: Decoder + Unpin> Framed<R, D> {
+    pub fn new(io: R, decoder: D) -> Self {
+        Framed { io: io, decoder: decoder, buf: vec!(), eof: false, done: false }
+    }
+
+    pub fn get_ref(&self) -> &R { &self.io }
+
+    pub fn get_mut(&mut self) -> &mut R { &mut self.io }
+
+    /// Returns the reader, and data that was read but not yet decoded.
+    pub fn into_parts(self) -> (R, Vec<u8>) { (self.io, self.buf) }
+}
+
+impl<R: AsyncRead + Unpin, D: Decoder + Unpin> Stream for Framed<R, D> {
+    type Item = io::Result<D::Item>;
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let s = self.get_mut();
+        loop {
+            if s.done { return Poll::Ready(None) }
+            let r = if s.eof { s.decoder.decode_eof(&mut s.buf) } else { s.decoder.decode(&mut s.buf) };
+            match r {
+                Ok(Some(x)) => return Poll::Ready(Some(Ok(x))),
+                Ok(None) if s.eof => { s.done = true; return Poll::Ready(None) },
+                Ok(None) => {},
+                Err(e) => { s.done = true; return Poll::Ready(Some(Err(e))) },
+            }
+            let mut chunk = [0u8; 4096];
+            match Pin::new(&mut s.io).poll_read(ctx, &mut chunk) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => s.eof = true,
+                Poll::Ready(Ok(n)) => s.buf.extend_from_slice(&chunk[..n]),
+                Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::Interrupted => {},
+                Poll::Ready(Err(e)) => { s.done = true; return Poll::Ready(Some(Err(e))) },
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn framed_lines() {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use futures::stream::StreamExt;
+    use crate::framed::{Lines, LengthDelimited};
+    use super::{Async, Executor};
+
+    let (a, mut b) = UnixStream::pair().unwrap();
+    b.write_all(b"one\r\ntwo\nthree").unwrap();
+    drop(b);
+    let mut x = Executor::new().unwrap();
+    let r: Vec<String> = x.block_on(async move {
+        Framed::new(Async::new(a).unwrap(), Lines::new()).map(|l| l.unwrap()).collect().await
+    }).unwrap();
+    assert_eq!(r, vec!("one", "two", "three"));
+
+    let (a, mut b) = UnixStream::pair().unwrap();
+    b.write_all(b"\x00\x00\x00\x02hi\x00\x00\x00\x05he").unwrap();
+    drop(b);
+    let r: Vec<_> = x.block_on(async move {
+        Framed::new(Async::new(a).unwrap(), LengthDelimited::new()).collect().await
+    }).unwrap();
+    assert_eq!(r[0].as_ref().unwrap(), b"hi");
+    assert_eq!(r.len(), 2);
+    assert_eq!(r[1].as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
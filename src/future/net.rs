@@ -0,0 +1,342 @@
+//! Async networking types for the Executor.
+//!
+//! These are thin wrappers around their std::net and std::os::unix::net counterparts,
+//! built on `Async`. Sockets are put into non-blocking mode when created.
+//! Functions taking `ToSocketAddrs` resolve the address synchronously.
+
+use std::io;
+use std::net::{self, SocketAddr, ToSocketAddrs, Shutdown};
+use std::os::unix::net as unet;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::mem;
+use futures::future::poll_fn;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use crate::IODirection;
+use super::Async;
+
+macro_rules! impl_async_rw {
+    ($t: ty) => {
+        impl AsyncRead for $t {
+            fn poll_read(mut self: Pin<&mut Self>, ctx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_read(ctx, buf)
+            }
+        }
+
+        impl AsyncWrite for $t {
+            fn poll_write(mut self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_write(ctx, buf)
+            }
+            fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_flush(ctx)
+            }
+            fn poll_close(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_close(ctx)
+            }
+        }
+
+        impl AsRawFd for $t {
+            fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+        }
+    }
+}
+
+// Tries the addresses one by one, returns the first success or the last error.
+fn each_addr<A: ToSocketAddrs, R, F: FnMut(SocketAddr) -> io::Result<R>>(addr: A, mut f: F) -> io::Result<R> {
+    let mut err = io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to");
+    for a in addr.to_socket_addrs()? {
+        match f(a) {
+            Ok(r) => return Ok(r),
+            Err(e) => err = e,
+        }
+    }
+    Err(err)
+}
+
+// Creates a non-blocking socket and starts connecting it.
+fn start_connect(addr: SocketAddr) -> io::Result<net::TcpStream> {
+    let (family, storage, len) = unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        match addr {
+            SocketAddr::V4(a) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+                (libc::AF_INET, storage, mem::size_of::<libc::sockaddr_in>())
+            }
+            SocketAddr::V6(a) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                (libc::AF_INET6, storage, mem::size_of::<libc::sockaddr_in6>())
+            }
+        }
+    };
+    // Close on exec, like std's sockets, so they do not leak into child processes
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+    if fd < 0 { return Err(io::Error::last_os_error()) }
+    let stream = unsafe { net::TcpStream::from_raw_fd(fd) };
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 { return Err(io::Error::last_os_error()) }
+    stream.set_nonblocking(true)?;
+    let r = unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len as libc::socklen_t) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) { return Err(e) }
+    }
+    Ok(stream)
+}
+
+/// A TCP stream, implementing AsyncRead and AsyncWrite.
+pub struct TcpStream(Async<net::TcpStream>);
+
+impl TcpStream {
+    /// Connects to a remote host, without blocking the main loop while the connection is established.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for a in addr.to_socket_addrs()? {
+            let mut s = match start_connect(a).and_then(Async::new) {
+                Ok(s) => s,
+                Err(e) => { last_err = Some(e); continue; }
+            };
+            // The socket becomes writable when the connection attempt has finished
+            let r = poll_fn(|ctx| s.poll_with(IODirection::Write, ctx, |io| {
+                if let Some(e) = io.take_error()? { return Err(e) }
+                match io.peer_addr() {
+                    Ok(_) => Ok(()),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Err(io::ErrorKind::WouldBlock.into()),
+                    Err(e) => Err(e),
+                }
+            })).await;
+            match r {
+                Ok(()) => return Ok(TcpStream(s)),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to")))
+    }
+
+    /// Wraps an already connected std TcpStream.
+    pub fn from_std(s: net::TcpStream) -> io::Result<TcpStream> { Ok(TcpStream(Async::new(s)?)) }
+
+    pub fn get_ref(&self) -> &net::TcpStream { self.0.get_ref() }
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().peer_addr() }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().local_addr() }
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.0.get_ref().shutdown(how) }
+}
+
+impl_async_rw!(TcpStream);
+
+/// A TCP listener, accepting incoming connections.
+pub struct TcpListener(Async<net::TcpListener>);
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        Ok(TcpListener(Async::new(net::TcpListener::bind(addr)?)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().local_addr() }
+
+    /// Waits for an incoming connection.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (s, a) = poll_fn(|ctx| self.poll_accept(ctx)).await?;
+        Ok((TcpStream::from_std(s)?, a))
+    }
+
+    fn poll_accept(&mut self, ctx: &mut Context) -> Poll<io::Result<(net::TcpStream, SocketAddr)>> {
+        self.0.poll_with(IODirection::Read, ctx, |io| io.accept())
+    }
+
+    /// A stream of incoming connections.
+    pub fn incoming(&mut self) -> Incoming<'_> { Incoming(self) }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+/// Stream of incoming connections, see `TcpListener::incoming`.
+pub struct Incoming<'a>(&'a mut TcpListener);
+
+impl Stream for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.poll_accept(ctx).map(|r| Some(r.and_then(|(s, _)| TcpStream::from_std(s))))
+    }
+}
+
+/// A UDP socket.
+pub struct UdpSocket(Async<net::UdpSocket>);
+
+impl UdpSocket {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+        Ok(UdpSocket(Async::new(net::UdpSocket::bind(addr)?)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().local_addr() }
+
+    /// Sets the default destination for `send`, and the only source for `recv`.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        each_addr(addr, |a| self.0.get_ref().connect(a))
+    }
+
+    pub async fn send_to<A: ToSocketAddrs>(&mut self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let addr = each_addr(addr, Ok)?;
+        poll_fn(|ctx| self.0.poll_with(IODirection::Write, ctx, |io| io.send_to(buf, addr))).await
+    }
+
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|ctx| self.0.poll_with(IODirection::Read, ctx, |io| io.recv_from(buf))).await
+    }
+
+    pub async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        poll_fn(|ctx| self.0.poll_with(IODirection::Write, ctx, |io| io.send(buf))).await
+    }
+
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|ctx| self.0.poll_with(IODirection::Read, ctx, |io| io.recv(buf))).await
+    }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+/// A Unix domain stream socket, implementing AsyncRead and AsyncWrite.
+pub struct UnixStream(Async<unet::UnixStream>);
+
+impl UnixStream {
+    /// Connects to a Unix socket.
+    ///
+    /// Connecting to a local socket does not wait for the other end to accept the connection.
+    pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<UnixStream> {
+        UnixStream::from_std(unet::UnixStream::connect(path)?)
+    }
+
+    /// Creates a pair of connected sockets.
+    pub fn pair() -> io::Result<(UnixStream, UnixStream)> {
+        let (a, b) = unet::UnixStream::pair()?;
+        Ok((UnixStream::from_std(a)?, UnixStream::from_std(b)?))
+    }
+
+    pub fn from_std(s: unet::UnixStream) -> io::Result<UnixStream> { Ok(UnixStream(Async::new(s)?)) }
+
+    pub fn get_ref(&self) -> &unet::UnixStream { self.0.get_ref() }
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.0.get_ref().shutdown(how) }
+}
+
+impl_async_rw!(UnixStream);
+
+/// A Unix domain socket listener.
+pub struct UnixListener(Async<unet::UnixListener>);
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+        Ok(UnixListener(Async::new(unet::UnixListener::bind(path)?)?))
+    }
+
+    /// Waits for an incoming connection.
+    pub async fn accept(&mut self) -> io::Result<UnixStream> {
+        poll_fn(|ctx| self.poll_accept(ctx)).await
+    }
+
+    fn poll_accept(&mut self, ctx: &mut Context) -> Poll<io::Result<UnixStream>> {
+        self.0.poll_with(IODirection::Read, ctx, |io| io.accept())
+            .map(|r| r.and_then(|(s, _)| UnixStream::from_std(s)))
+    }
+
+    /// A stream of incoming connections.
+    pub fn incoming(&mut self) -> UnixIncoming<'_> { UnixIncoming(self) }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+/// Stream of incoming connections, see `UnixListener::incoming`.
+pub struct UnixIncoming<'a>(&'a mut UnixListener);
+
+impl Stream for UnixIncoming<'_> {
+    type Item = io::Result<UnixStream>;
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.0.poll_accept(ctx).map(Some)
+    }
+}
+
+#[test]
+fn tcp_echo() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::stream::StreamExt;
+    use super::Executor;
+
+    let mut x = Executor::new().unwrap();
+    let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    x.spawn(async move {
+        let mut s = listener.incoming().next().await.unwrap().unwrap();
+        let mut buf = [0u8; 5];
+        s.read_exact(&mut buf).await.unwrap();
+        s.write_all(&buf).await.unwrap();
+    }).detach();
+    let r = x.block_on(async move {
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(s.peer_addr().unwrap(), addr);
+        // Must not leak into child processes
+        assert!(unsafe { libc::fcntl(s.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC != 0);
+        s.write_all(b"Hello").await.unwrap();
+        let mut v = vec!();
+        s.read_to_end(&mut v).await.unwrap();
+        v
+    });
+    assert_eq!(r.unwrap(), b"Hello");
+}
+
+#[test]
+fn tcp_connect_refused() {
+    use super::Executor;
+
+    let addr = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    // The listener is now closed
+    let mut x = Executor::new().unwrap();
+    let r = x.block_on(TcpStream::connect(addr)).unwrap();
+    assert_eq!(r.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn udp_and_unix() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use super::Executor;
+
+    let mut x = Executor::new().unwrap();
+    let path = std::env::temp_dir().join(format!("thin_main_loop_test_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut listener = UnixListener::bind(&path).unwrap();
+    let path2 = path.clone();
+    let r = x.block_on(async move {
+        let mut a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.send_to(b"Ping", b.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (n, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, a.local_addr().unwrap());
+
+        let mut c = UnixStream::connect(&path2).await.unwrap();
+        let mut s = listener.accept().await.unwrap();
+        c.write_all(&buf[..n]).await.unwrap();
+        let mut buf2 = [0u8; 4];
+        s.read_exact(&mut buf2).await.unwrap();
+        buf2
+    });
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&r.unwrap(), b"Ping");
+}
@@ -0,0 +1,162 @@
+//! Child processes with async pipes and exit status.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::process::{self, ExitStatus, Output, Stdio};
+use std::rc::Rc;
+use futures::future::{self, Future};
+use futures::io::AsyncReadExt;
+use futures::task::{Context, Poll, Waker};
+use crate::CbId;
+use super::Async;
+
+pub type ChildStdin = Async<process::ChildStdin>;
+pub type ChildStdout = Async<process::ChildStdout>;
+pub type ChildStderr = Async<process::ChildStderr>;
+
+/// A builder for child processes, like std::process::Command.
+#[derive(Debug)]
+pub struct Command(process::Command);
+
+impl Command {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self { Command(process::Command::new(program)) }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self { self.0.arg(arg); self }
+
+    pub fn args<I: IntoIterator<Item=S>, S: AsRef<OsStr>>(&mut self, args: I) -> &mut Self { self.0.args(args); self }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self { self.0.env(key, val); self }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self { self.0.current_dir(dir); self }
+
+    pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self { self.0.stdin(cfg); self }
+
+    pub fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self { self.0.stdout(cfg); self }
+
+    pub fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Self { self.0.stderr(cfg); self }
+
+    /// Access to the underlying std Command, for options not covered here.
+    pub fn as_std_mut(&mut self) -> &mut process::Command { &mut self.0 }
+
+    /// Starts the process. Pipes set up with Stdio::piped() are available as async streams.
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        let mut child = self.0.spawn()?;
+        Ok(Child {
+            stdin: child.stdin.take().map(Async::new).transpose()?,
+            stdout: child.stdout.take().map(Async::new).transpose()?,
+            stderr: child.stderr.take().map(Async::new).transpose()?,
+            child: child,
+            exit: Default::default(),
+        })
+    }
+
+    /// Starts the process and waits for it to exit.
+    pub async fn status(&mut self) -> io::Result<ExitStatus> {
+        self.spawn()?.wait().await
+    }
+
+    /// Starts the process, collects its output and waits for it to exit.
+    ///
+    /// This overrides earlier stdin, stdout and stderr settings: stdin is null
+    /// and the others are captured.
+    pub async fn output(&mut self) -> io::Result<Output> {
+        self.0.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = self.spawn()?;
+        let (mut out, mut err) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+        let (mut stdout, mut stderr) = (vec!(), vec!());
+        let (r1, r2) = future::join(out.read_to_end(&mut stdout), err.read_to_end(&mut stderr)).await;
+        r1?;
+        r2?;
+        let status = child.wait().await?;
+        Ok(Output { status: status, stdout: stdout, stderr: stderr })
+    }
+}
+
+impl From<process::Command> for Command {
+    fn from(c: process::Command) -> Self { Command(c) }
+}
+
+#[derive(Default)]
+struct ExitState {
+    status: Cell<Option<ExitStatus>>,
+    waker: RefCell<Option<Waker>>,
+    id: Cell<Option<CbId>>,
+}
+
+/// A running child process.
+///
+/// The process is not killed or waited for when this is dropped.
+pub struct Child {
+    child: process::Child,
+    exit: Rc<ExitState>,
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Option<ChildStdout>,
+    pub stderr: Option<ChildStderr>,
+}
+
+impl Child {
+    pub fn id(&self) -> u32 { self.child.id() }
+
+    /// Sends SIGKILL to the process, unless it has already exited.
+    pub fn kill(&mut self) -> io::Result<()> {
+        if self.exit.status.get().is_some() { return Ok(()) }
+        self.child.kill()
+    }
+
+    /// The exit status, if the process has exited and `wait` has noticed.
+    pub fn try_status(&self) -> Option<ExitStatus> { self.exit.status.get() }
+
+    fn poll_wait(&mut self, ctx: &mut Context) -> Poll<io::Result<ExitStatus>> {
+        if let Some(s) = self.exit.status.get() { return Poll::Ready(Ok(s)) }
+        *self.exit.waker.borrow_mut() = Some(ctx.waker().clone());
+        if self.exit.id.get().is_none() {
+            let exit = self.exit.clone();
+            let id = crate::call_child_watch(self.child.id() as libc::pid_t, move |s| {
+                exit.status.set(Some(s));
+                exit.id.set(None);
+                if let Some(w) = exit.waker.borrow_mut().take() { w.wake() }
+            })?;
+            self.exit.id.set(Some(id));
+        }
+        Poll::Pending
+    }
+
+    /// Waits for the process to exit.
+    ///
+    /// The stdin pipe, if any, is closed first so the process does not wait for more input.
+    pub fn wait(&mut self) -> impl Future<Output=io::Result<ExitStatus>> + '_ {
+        self.stdin.take();
+        future::poll_fn(move |ctx| self.poll_wait(ctx))
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Some(id) = self.exit.id.take() { let _ = crate::cancel(id); }
+    }
+}
+
+#[test]
+fn process_output() {
+    use futures::io::AsyncWriteExt;
+    use super::Executor;
+
+    let mut x = Executor::new().unwrap();
+    let r = x.block_on(async {
+        let o = Command::new("sh").args(["-c", "echo out; echo err >&2; exit 2"]).output().await.unwrap();
+        assert_eq!(o.stdout, b"out\n");
+        assert_eq!(o.stderr, b"err\n");
+        assert_eq!(o.status.code(), Some(2));
+
+        let mut child = Command::new("cat").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.as_mut().unwrap().write_all(b"Hello").await.unwrap();
+        let s = child.wait().await.unwrap();
+        let mut v = vec!();
+        child.stdout.as_mut().unwrap().read_to_end(&mut v).await.unwrap();
+        assert!(s.success());
+        v
+    }).unwrap();
+    assert_eq!(r, b"Hello");
+}
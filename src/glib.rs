@@ -1,9 +1,12 @@
-use crate::{CbKind, CbId, MainLoopError, IODirection};
+use crate::{CbKind, CbId, CbHandle, MainLoopError, IODirection, IOEvent, IOMode, EventSource};
+use std::os::raw::c_int;
+use std::time::{Instant, Duration};
 use glib_sys;
 use std::{mem, panic};
 use std::ptr::NonNull;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
 use crate::mainloop::{SendFnOnce, ffi_cb_wrapper};
-use std::os::raw::c_uint;
 
 use std::cell::RefCell;
 use std::collections::{HashMap};
@@ -17,6 +20,37 @@ const G_SOURCE_FUNCS: glib_sys::GSourceFuncs = glib_sys::GSourceFuncs {
     closure_marshal: None, // GSourceDummyMarshal,
 };
 
+// For MultiIO callbacks, which can have several fds and a ready time
+const G_MULTI_SOURCE_FUNCS: glib_sys::GSourceFuncs = glib_sys::GSourceFuncs {
+    prepare: None,
+    check: Some(glib_multi_check_cb),
+    dispatch: Some(glib_multi_dispatch_cb),
+    finalize: Some(glib_source_finalize_cb),
+    closure_callback: None,
+    closure_marshal: None,
+};
+
+// For At and Interval callbacks. Unlike g_timeout_source_new, the ready time has microsecond resolution,
+// but GLib still polls with a timeout in whole milliseconds, rounded upwards.
+const G_TIMER_SOURCE_FUNCS: glib_sys::GSourceFuncs = glib_sys::GSourceFuncs {
+    prepare: None,
+    check: None,
+    dispatch: Some(glib_timer_dispatch_cb),
+    finalize: Some(glib_source_finalize_cb),
+    closure_callback: None,
+    closure_marshal: None,
+};
+
+// For EventSource callbacks
+const G_EVENT_SOURCE_FUNCS: glib_sys::GSourceFuncs = glib_sys::GSourceFuncs {
+    prepare: Some(glib_event_prepare_cb),
+    check: Some(glib_event_check_cb),
+    dispatch: Some(glib_event_dispatch_cb),
+    finalize: Some(glib_source_finalize_cb),
+    closure_callback: None,
+    closure_marshal: None,
+};
+
 #[repr(C)]
 struct GSourceIOData {
     gsource: glib_sys::GSource,
@@ -28,6 +62,8 @@ struct CbData<'a> {
     gsource: GSourceRef,
     cbid: CbId,
     kind: RefCell<Option<CbKind<'a>>>,
+    // The fds of MultiIO callbacks
+    tags: RefCell<Vec<(CbHandle, glib_sys::gpointer)>>,
 }
 
 struct GSourceRef(NonNull<glib_sys::GSource>);
@@ -66,6 +102,8 @@ unsafe extern "C" fn glib_source_check_cb(gs: *mut glib_sys::GSource) -> glib_sy
             let ss: &mut GSourceIOData = &mut *(gs as *mut _);
             ss.tag
         };
+        // A one-shot source that has not been re-armed
+        if tag.is_null() { return glib_sys::GFALSE }
         let cond = glib_sys::g_source_query_unix_fd(gs, tag);
         // println!("Check {:?} {:?}!", tag, cond);
         if cond == 0 { glib_sys::GFALSE } else { glib_sys::GTRUE }
@@ -76,20 +114,146 @@ unsafe extern "C" fn glib_source_dispatch_cb(gs: *mut glib_sys::GSource, _: glib
     ffi_cb_wrapper(|| {
         let ss: &mut GSourceIOData = &mut *(gs as *mut _);
         let cond = glib_sys::g_source_query_unix_fd(gs, ss.tag);
-        let dir = gio_to_dir(cond);
+        let ev = gio_to_event(cond);
 
         let r = false;
-        if let Some(mut cb_data) = &ss.cb_data {
-            if cbdata_call(cb_data.as_mut(), Some(dir)) { return glib_sys::GTRUE; }
+        if let Some(mut cb_data) = ss.cb_data {
+            let cb_data = cb_data.as_mut();
+            if cbdata_call(cb_data, |kind| kind.call_mut(Some(ev))) {
+                let mode = cb_data.kind.borrow().as_ref().and_then(|k| k.io_mode());
+                if mode == Some(IOMode::OneShot) {
+                    // Stop polling the fd until set_io_direction re-arms it
+                    glib_sys::g_source_remove_unix_fd(gs, ss.tag);
+                    ss.tag = std::ptr::null_mut();
+                }
+                return glib_sys::GTRUE;
+            }
+        }
+        ss.cb_data.take();
+        glib_sys::GFALSE
+   }, glib_sys::GFALSE)
+}
+
+unsafe extern "C" fn glib_multi_check_cb(gs: *mut glib_sys::GSource) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        let ss: &mut GSourceIOData = &mut *(gs as *mut _);
+        let cb_data = match ss.cb_data {
+            Some(cb_data) => cb_data,
+            None => return glib_sys::GFALSE,
+        };
+        // The deadline is handled by GLib, through the ready time
+        let ready = cb_data.as_ref().tags.borrow().iter().any(|(_, tag)| glib_sys::g_source_query_unix_fd(gs, *tag) != 0);
+        if ready { glib_sys::GTRUE } else { glib_sys::GFALSE }
+   }, glib_sys::GFALSE)
+}
+
+unsafe extern "C" fn glib_multi_dispatch_cb(gs: *mut glib_sys::GSource, _: glib_sys::GSourceFunc, _: glib_sys::gpointer) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        let ss: &mut GSourceIOData = &mut *(gs as *mut _);
+        if let Some(mut cb_data) = ss.cb_data {
+            let cb_data = cb_data.as_mut();
+            let ready: Vec<_> = cb_data.tags.borrow().iter().filter_map(|(h, tag)| {
+                let cond = glib_sys::g_source_query_unix_fd(gs, *tag);
+                if cond == 0 { None } else { Some((*h, gio_to_event(cond))) }
+            }).collect();
+            if cbdata_call(cb_data, |kind| kind.call_multi(&ready)) {
+                sync_multi(gs, cb_data);
+                return glib_sys::GTRUE;
+            }
+        }
+        ss.cb_data.take();
+        glib_sys::GFALSE
+   }, glib_sys::GFALSE)
+}
+
+// Calls f with the EventSource of a GSource, or returns on_none if it has finished.
+unsafe fn with_event_source<R, F: FnOnce(&mut dyn EventSource) -> R>(gs: *mut glib_sys::GSource, on_none: R, f: F) -> R {
+    let ss: &mut GSourceIOData = &mut *(gs as *mut _);
+    let cb_data = match ss.cb_data {
+        Some(cb_data) => cb_data,
+        None => return on_none,
+    };
+    let mut kind = cb_data.as_ref().kind.borrow_mut();
+    match &mut *kind {
+        Some(CbKind::Source(s)) => f(&mut **s),
+        _ => on_none,
+    }
+}
+
+unsafe extern "C" fn glib_event_prepare_cb(gs: *mut glib_sys::GSource, timeout: *mut c_int) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        let t = with_event_source(gs, None, |s| s.prepare());
+        *timeout = match t {
+            // Round upwards, so check is not called too early
+            Some(t) => std::cmp::min(t.as_micros().div_ceil(1000), c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+        // Readiness is always decided by check
+        glib_sys::GFALSE
+   }, glib_sys::GFALSE)
+}
+
+unsafe extern "C" fn glib_event_check_cb(gs: *mut glib_sys::GSource) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        if with_event_source(gs, false, |s| s.check()) { glib_sys::GTRUE } else { glib_sys::GFALSE }
+   }, glib_sys::GFALSE)
+}
+
+unsafe extern "C" fn glib_event_dispatch_cb(gs: *mut glib_sys::GSource, _: glib_sys::GSourceFunc, _: glib_sys::gpointer) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        let ss: &mut GSourceIOData = &mut *(gs as *mut _);
+        if let Some(cb_data) = ss.cb_data {
+            if cbdata_call(cb_data.as_ref(), |kind| kind.call_mut(None)) { return glib_sys::GTRUE; }
         }
         ss.cb_data.take();
         glib_sys::GFALSE
    }, glib_sys::GFALSE)
 }
 
-fn cbdata_call(cb_data: &CbData, dir: Option<Result<IODirection, std::io::Error>>) -> bool {
+unsafe extern "C" fn glib_timer_dispatch_cb(gs: *mut glib_sys::GSource, _: glib_sys::GSourceFunc, _: glib_sys::gpointer) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        let ss: &mut GSourceIOData = &mut *(gs as *mut _);
+        if let Some(cb_data) = ss.cb_data {
+            let cb_data = cb_data.as_ref();
+            if cbdata_call(cb_data, |kind| kind.call_mut(None)) {
+                // Count from the previous deadline rather than from now, so intervals do not drift
+                let d = cb_data.kind.borrow().as_ref().and_then(|k| k.period()).unwrap_or_default();
+                glib_sys::g_source_set_ready_time(gs, glib_sys::g_source_get_ready_time(gs) + duration_to_micros(d));
+                return glib_sys::GTRUE;
+            }
+        }
+        ss.cb_data.take();
+        glib_sys::GFALSE
+   }, glib_sys::GFALSE)
+}
+
+fn duration_to_micros(d: Duration) -> i64 {
+    std::cmp::min(d.as_micros(), i64::MAX as u128 / 2) as i64
+}
+
+// Makes the GSource watch the current handles and deadline of a MultiIO callback.
+unsafe fn sync_multi(gs: *mut glib_sys::GSource, cb_data: &CbData) {
+    let mut tags = cb_data.tags.borrow_mut();
+    for (_, tag) in tags.drain(..) {
+        glib_sys::g_source_remove_unix_fd(gs, tag);
+    }
+    let kind = cb_data.kind.borrow();
+    let kind = match &*kind {
+        Some(kind) => kind,
+        None => return,
+    };
+    for (h, dir) in kind.multi_handles().unwrap_or_default() {
+        tags.push((h, glib_sys::g_source_add_unix_fd(gs, h.0, dir_to_gio(dir))));
+    }
+    let ready_time = kind.deadline().map(|d| {
+        glib_sys::g_get_monotonic_time() + duration_to_micros(d.saturating_duration_since(Instant::now()))
+    }).unwrap_or(-1);
+    glib_sys::g_source_set_ready_time(gs, ready_time);
+}
+
+fn cbdata_call<F: FnOnce(&mut CbKind) -> bool>(cb_data: &CbData, call: F) -> bool {
     if let Some(ref mut kind) = *cb_data.kind.borrow_mut() {
-        if kind.call_mut(dir) { return true; }
+        if call(kind) { return true; }
     };
     cb_data.kind.borrow_mut().take().map(|kind| { kind.post_call_mut(); });
     FINISHED_TLS.with(|f| { f.borrow_mut().push(cb_data.cbid); });
@@ -99,30 +263,39 @@ fn cbdata_call(cb_data: &CbData, dir: Option<Result<IODirection, std::io::Error>
 fn dir_to_gio(d: IODirection) -> glib_sys::GIOCondition {
     glib_sys::G_IO_HUP + glib_sys::G_IO_ERR + match d {
         IODirection::None => 0,
-        IODirection::Read => glib_sys::G_IO_IN,
+        IODirection::Read => glib_sys::G_IO_IN + glib_sys::G_IO_PRI,
         IODirection::Write => glib_sys::G_IO_OUT,
-        IODirection::Both => glib_sys::G_IO_IN + glib_sys::G_IO_OUT,
+        IODirection::Both => glib_sys::G_IO_IN + glib_sys::G_IO_PRI + glib_sys::G_IO_OUT,
     }
 }
 
-fn gio_to_dir(cond: glib_sys::GIOCondition) -> Result<IODirection, std::io::Error> {
-    const BOTH: c_uint = glib_sys::G_IO_IN + glib_sys::G_IO_OUT;
-    match cond {
-       0 => Ok(IODirection::None),
-       glib_sys::G_IO_IN => Ok(IODirection::Read),
-       glib_sys::G_IO_OUT => Ok(IODirection::Write),
-       BOTH => Ok(IODirection::Both),
-       _ => unimplemented!(),
+fn gio_to_event(cond: glib_sys::GIOCondition) -> IOEvent {
+    IOEvent {
+        readable: cond & glib_sys::G_IO_IN != 0,
+        writable: cond & glib_sys::G_IO_OUT != 0,
+        hangup: cond & glib_sys::G_IO_HUP != 0,
+        error: cond & (glib_sys::G_IO_ERR | glib_sys::G_IO_NVAL) != 0,
+        priority: cond & glib_sys::G_IO_PRI != 0,
     }
 }
 
 unsafe extern fn glib_cb(x: glib_sys::gpointer) -> glib_sys::gboolean {
     ffi_cb_wrapper(|| {
         let x = x as *const _ as *mut CbData;
-        if cbdata_call(&mut (*x), None) { glib_sys::GTRUE } else { glib_sys::GFALSE }
+        if cbdata_call(&mut (*x), |kind| kind.call_mut(None)) { glib_sys::GTRUE } else { glib_sys::GFALSE }
    }, glib_sys::GFALSE)
 }
 
+// GLib has already reaped the child, and removes the source after this call
+unsafe extern fn glib_child_cb(_: glib_sys::GPid, status: c_int, x: glib_sys::gpointer) {
+    ffi_cb_wrapper(|| {
+        let x = &*(x as *const CbData);
+        let kind = x.kind.borrow_mut().take();
+        if let Some(kind) = kind { kind.post_call_child(ExitStatus::from_raw(status)); }
+        FINISHED_TLS.with(|f| { f.borrow_mut().push(x.cbid); });
+    }, ())
+}
+
 struct Dummy(Box<dyn FnOnce() + Send + 'static>);
 
 struct Sender(*mut glib_sys::GMainContext);
@@ -175,32 +348,28 @@ impl<'a> Backend<'a> {
         let sender = Sender(unsafe { glib_sys::g_main_context_ref(be.ctx) }); 
         Ok((be, Box::new(sender)))
     }
+}
 
-    pub fn run_one(&self, wait: bool) -> bool {
-        let w = if wait { glib_sys::GTRUE } else { glib_sys::GFALSE };
-        let r = unsafe { glib_sys::g_main_context_iteration(self.ctx, w) != glib_sys::GFALSE };
-        FINISHED_TLS.with(|f| {
-            for cbid in f.borrow_mut().drain(..) {
-                self.cb_map.borrow_mut().remove(&cbid);
-            };
-        });
-        r
-    }
-
-    pub (crate) fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
-        self.cb_map.borrow_mut().remove(&cbid)
-        .and_then(|s| { s.kind.borrow_mut().take() })
-    }
-
-    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+impl<'a> crate::mainloop::Backend<'a> for Backend<'a> {
+    fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
         let mut tag = None;
+        let multi = cb.multi_handles().is_some();
+        let event_source = matches!(cb, CbKind::Source(_));
+        let duration = cb.duration();
+        let child = cb.child_pid();
         let s = unsafe { 
-            if let Some((handle, direction)) = cb.handle() {
+            if let Some(pid) = child {
+                glib_sys::g_child_watch_source_new(pid)
+            } else if let Some((handle, direction)) = cb.handle() {
                 let s = glib_sys::g_source_new(&G_SOURCE_FUNCS as *const _ as *mut _, mem::size_of::<GSourceIOData>() as u32);
                 tag = Some(glib_sys::g_source_add_unix_fd(s, handle.0, dir_to_gio(direction)));
                 s
-            } else if let Some(s) = cb.duration_millis()? {
-                glib_sys::g_timeout_source_new(s)
+            } else if multi {
+                glib_sys::g_source_new(&G_MULTI_SOURCE_FUNCS as *const _ as *mut _, mem::size_of::<GSourceIOData>() as u32)
+            } else if event_source {
+                glib_sys::g_source_new(&G_EVENT_SOURCE_FUNCS as *const _ as *mut _, mem::size_of::<GSourceIOData>() as u32)
+            } else if duration.is_some() {
+                glib_sys::g_source_new(&G_TIMER_SOURCE_FUNCS as *const _ as *mut _, mem::size_of::<GSourceIOData>() as u32)
             } else {
                 glib_sys::g_idle_source_new()
             }
@@ -210,15 +379,30 @@ impl<'a> Backend<'a> {
             gsource: GSourceRef(NonNull::new(s).unwrap()),
             cbid: cbid,
             kind: RefCell::new(Some(cb)),
+            tags: Default::default(),
         });
         let x = NonNull::from(&*boxed);
         self.cb_map.borrow_mut().insert(cbid, boxed);
 
         unsafe {
-            if let Some(tag) = tag {
+            if child.is_some() {
+                let f: glib_sys::GChildWatchFunc = Some(glib_child_cb);
+                glib_sys::g_source_set_callback(s, mem::transmute::<glib_sys::GChildWatchFunc, glib_sys::GSourceFunc>(f), x.as_ptr() as *mut _ as *mut _, None);
+            } else if let Some(tag) = tag {
                 let ss: &mut GSourceIOData = &mut *(s as *mut _);
                 ss.cb_data = Some(x.cast());
                 ss.tag = tag;
+            } else if multi {
+                let ss: &mut GSourceIOData = &mut *(s as *mut _);
+                ss.cb_data = Some(x.cast());
+                sync_multi(s, x.as_ref());
+            } else if event_source {
+                let ss: &mut GSourceIOData = &mut *(s as *mut _);
+                ss.cb_data = Some(x.cast());
+            } else if let Some(d) = duration {
+                let ss: &mut GSourceIOData = &mut *(s as *mut _);
+                ss.cb_data = Some(x.cast());
+                glib_sys::g_source_set_ready_time(s, glib_sys::g_get_monotonic_time() + duration_to_micros(d));
             } else {
                 glib_sys::g_source_set_callback(s, Some(glib_cb), x.as_ptr() as *mut _ as *mut _, None);
             }
@@ -228,5 +412,42 @@ impl<'a> Backend<'a> {
         }
         Ok(())
     }
+
+    fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+        self.cb_map.borrow_mut().remove(&cbid)
+        .and_then(|s| { s.kind.borrow_mut().take() })
+    }
+
+    fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        let map = self.cb_map.borrow();
+        let cb_data = map.get(&cbid).ok_or_else(|| crate::mainloop::not_an_io_callback(cbid))?;
+        let handle = cb_data.kind.borrow().as_ref().and_then(|k| k.handle());
+        let fd = handle.ok_or_else(|| crate::mainloop::not_an_io_callback(cbid))?.0.0;
+        unsafe {
+            let s = cb_data.gsource.0.as_ptr();
+            let ss: &mut GSourceIOData = &mut *(s as *mut _);
+            if ss.tag.is_null() {
+                ss.tag = glib_sys::g_source_add_unix_fd(s, fd, dir_to_gio(dir));
+            } else {
+                glib_sys::g_source_modify_unix_fd(s, ss.tag, dir_to_gio(dir));
+            }
+        }
+        Ok(())
+    }
+
+    fn supports_sources(&self) -> bool { true }
+
+    fn supports_child_watch(&self) -> bool { true }
+
+    fn run_one(&self, wait: bool) -> bool {
+        let w = if wait { glib_sys::GTRUE } else { glib_sys::GFALSE };
+        let r = unsafe { glib_sys::g_main_context_iteration(self.ctx, w) != glib_sys::GFALSE };
+        FINISHED_TLS.with(|f| {
+            for cbid in f.borrow_mut().drain(..) {
+                self.cb_map.borrow_mut().remove(&cbid);
+            };
+        });
+        r
+    }
 }
 
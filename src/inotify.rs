@@ -0,0 +1,291 @@
+//! Filesystem change notifications, through inotify.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::BitOr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use crate::{CbHandle, IOAble, IODirection, IOEvent, MainLoopError};
+
+/// Which changes to watch for. Combine with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchMask {
+    bits: u32,
+    recursive: bool,
+}
+
+impl WatchMask {
+    pub const CREATE: WatchMask = WatchMask { bits: libc::IN_CREATE, recursive: false };
+    pub const MODIFY: WatchMask = WatchMask { bits: libc::IN_MODIFY, recursive: false };
+    /// A file opened for writing was closed. Useful for reloading files once the writer is done.
+    pub const CLOSE_WRITE: WatchMask = WatchMask { bits: libc::IN_CLOSE_WRITE, recursive: false };
+    /// Permissions, timestamps, ownership etc.
+    pub const ATTRIB: WatchMask = WatchMask { bits: libc::IN_ATTRIB, recursive: false };
+    pub const DELETE: WatchMask = WatchMask { bits: libc::IN_DELETE | libc::IN_DELETE_SELF, recursive: false };
+    pub const MOVE: WatchMask = WatchMask { bits: libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_MOVE_SELF, recursive: false };
+    pub const ALL: WatchMask = WatchMask { bits: libc::IN_CREATE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_ATTRIB |
+        libc::IN_DELETE | libc::IN_DELETE_SELF | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_MOVE_SELF, recursive: false };
+    /// Watch subdirectories too, including those created later.
+    pub const RECURSIVE: WatchMask = WatchMask { bits: 0, recursive: true };
+
+    pub fn contains(&self, other: WatchMask) -> bool {
+        self.bits & other.bits == other.bits && (self.recursive || !other.recursive)
+    }
+}
+
+impl BitOr for WatchMask {
+    type Output = WatchMask;
+    fn bitor(self, rhs: WatchMask) -> WatchMask {
+        WatchMask { bits: self.bits | rhs.bits, recursive: self.recursive || rhs.recursive }
+    }
+}
+
+/// A change to a watched file or directory.
+///
+/// Paths are the watched path joined with the name of the changed entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FsEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    ClosedWrite(PathBuf),
+    Attrib(PathBuf),
+    Deleted(PathBuf),
+    /// Renamed within the watched tree.
+    Moved { from: PathBuf, to: PathBuf },
+    /// Moved away from the watched tree, or the watched path itself was moved.
+    MovedFrom(PathBuf),
+    /// Moved into the watched tree from elsewhere.
+    MovedTo(PathBuf),
+    /// The kernel's event queue overflowed and events were lost. Rescan if you care.
+    Overflow,
+}
+
+// Events we need for bookkeeping even if the user did not ask for them
+const INTERNAL_BITS: u32 = libc::IN_CREATE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+
+// How many events after an IN_MOVED_FROM to look for its IN_MOVED_TO. The kernel queues them
+// right after each other, but leaves room for other events in between.
+const MOVE_LOOKAHEAD: usize = 4;
+
+/// An inotify instance watching a path, and optionally its subdirectories.
+pub (crate) struct Watcher {
+    fd: File,
+    mask: WatchMask,
+    root: i32,
+    wds: HashMap<i32, PathBuf>,
+}
+
+impl Watcher {
+    pub (crate) fn new(path: &Path, mask: WatchMask) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 { return Err(io::Error::last_os_error()) }
+        let mut w = Watcher { fd: unsafe { File::from_raw_fd(fd) }, mask: mask, root: -1, wds: HashMap::new() };
+        w.root = w.add(path, false)?;
+        if mask.recursive && path.is_dir() { w.add_subdirs(path); }
+        Ok(w)
+    }
+
+    fn add(&mut self, path: &Path, onlydir: bool) -> io::Result<i32> {
+        let mut bits = self.mask.bits;
+        if self.mask.recursive { bits |= INTERNAL_BITS }
+        if onlydir { bits |= libc::IN_ONLYDIR }
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), cpath.as_ptr(), bits) };
+        if wd < 0 { return Err(io::Error::last_os_error()) }
+        self.wds.insert(wd, path.into());
+        Ok(wd)
+    }
+
+    // Errors are ignored, the directory might be gone already.
+    fn add_subdirs(&mut self, dir: &Path) {
+        let entries = match std::fs::read_dir(dir) { Ok(e) => e, Err(_) => return };
+        for e in entries.flatten() {
+            if !e.file_type().map(|t| t.is_dir()).unwrap_or(false) { continue; }
+            let p = e.path();
+            if self.add(&p, true).is_ok() { self.add_subdirs(&p); }
+        }
+    }
+
+    fn remove_subtree(&mut self, dir: &Path) {
+        let fd = self.fd.as_raw_fd();
+        self.wds.retain(|&wd, p| {
+            if !p.starts_with(dir) { return true }
+            unsafe { libc::inotify_rm_watch(fd, wd); }
+            false
+        });
+    }
+
+    fn rename_subtree(&mut self, from: &Path, to: &Path) {
+        for p in self.wds.values_mut() {
+            if let Ok(rest) = p.strip_prefix(from) { *p = to.join(rest); }
+        }
+    }
+
+    /// Reads and decodes pending events. Returns an empty Vec if there are none.
+    pub (crate) fn read_events(&mut self) -> io::Result<Vec<FsEvent>> {
+        let mut buf = [0u8; 4096];
+        let mut raw = vec!();
+        loop {
+            let n = match self.fd.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let mut i = 0;
+            while i + std::mem::size_of::<libc::inotify_event>() <= n {
+                let ev: libc::inotify_event = unsafe { std::ptr::read_unaligned(buf[i..].as_ptr() as *const _) };
+                i += std::mem::size_of::<libc::inotify_event>();
+                let name = &buf[i..i + ev.len as usize];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                i += ev.len as usize;
+                raw.push((ev.wd, ev.mask, ev.cookie, OsStr::from_bytes(name).to_owned()));
+            }
+        }
+
+        let mut events = vec!();
+        // Moves are reported as two events with the same cookie; pair them up if both are in this batch.
+        // The IN_MOVED_TO events that have been paired up already.
+        let mut paired = vec!(false; raw.len());
+        let user = self.mask.bits;
+        for (i, &(wd, mask, cookie, ref name)) in raw.iter().enumerate() {
+            if paired[i] { continue; }
+            if mask & libc::IN_Q_OVERFLOW != 0 { events.push(FsEvent::Overflow); continue; }
+            if mask & libc::IN_IGNORED != 0 { self.wds.remove(&wd); continue; }
+            let dir = match self.wds.get(&wd) { Some(d) => d.clone(), None => continue };
+            let path = if name.is_empty() { dir } else { dir.join(name) };
+            let is_dir = mask & libc::IN_ISDIR != 0;
+
+            if mask & libc::IN_CREATE != 0 {
+                if is_dir && self.mask.recursive && self.add(&path, true).is_ok() { self.add_subdirs(&path); }
+                if user & libc::IN_CREATE != 0 { events.push(FsEvent::Created(path)); }
+            } else if mask & libc::IN_MOVED_FROM != 0 {
+                let to = raw.iter().enumerate().skip(i + 1).take(MOVE_LOOKAHEAD)
+                    .find(|(_, x)| x.1 & libc::IN_MOVED_TO != 0 && x.2 == cookie)
+                    .and_then(|(j, x)| self.wds.get(&x.0).map(|d| (j, d.join(&x.3))));
+                if let Some((j, to)) = to {
+                    paired[j] = true;
+                    if is_dir && self.mask.recursive { self.rename_subtree(&path, &to); }
+                    if user & libc::IN_MOVED_TO != 0 { events.push(FsEvent::Moved { from: path, to: to }); }
+                } else {
+                    // Moved out of the watched tree, or the other half is not in this batch
+                    if is_dir && self.mask.recursive { self.remove_subtree(&path); }
+                    if user & libc::IN_MOVED_FROM != 0 { events.push(FsEvent::MovedFrom(path)); }
+                }
+            } else if mask & libc::IN_MOVED_TO != 0 {
+                if is_dir && self.mask.recursive && self.add(&path, true).is_ok() { self.add_subdirs(&path); }
+                if user & libc::IN_MOVED_TO != 0 { events.push(FsEvent::MovedTo(path)); }
+            } else if mask & libc::IN_MODIFY != 0 {
+                events.push(FsEvent::Modified(path));
+            } else if mask & libc::IN_CLOSE_WRITE != 0 {
+                events.push(FsEvent::ClosedWrite(path));
+            } else if mask & libc::IN_ATTRIB != 0 {
+                events.push(FsEvent::Attrib(path));
+            } else if mask & libc::IN_DELETE != 0 || (wd == self.root && mask & libc::IN_DELETE_SELF != 0) {
+                events.push(FsEvent::Deleted(path));
+            } else if wd == self.root && mask & libc::IN_MOVE_SELF != 0 {
+                events.push(FsEvent::MovedFrom(path));
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd { self.fd.as_raw_fd() }
+}
+
+pub (crate) struct PathWatch<F: FnMut(FsEvent) -> bool> {
+    watcher: Watcher,
+    f: F,
+}
+
+impl<F: FnMut(FsEvent) -> bool> PathWatch<F> {
+    pub (crate) fn new(path: &Path, mask: WatchMask, f: F) -> Result<Self, MainLoopError> {
+        let w = Watcher::new(path, mask).map_err(|e| MainLoopError::Other(e.into()))?;
+        Ok(PathWatch { watcher: w, f: f })
+    }
+}
+
+impl<F: FnMut(FsEvent) -> bool> IOAble for PathWatch<F> {
+    fn handle(&self) -> CbHandle { CbHandle(self.watcher.as_raw_fd()) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: IOEvent) -> bool {
+        let events = match self.watcher.read_events() { Ok(e) => e, Err(_) => return false };
+        events.into_iter().all(|e| (self.f)(e))
+    }
+}
+
+#[test]
+fn watch_path() {
+    use std::cell::RefCell;
+    use std::time::Duration;
+    use crate::MainLoop;
+
+    let dir = std::env::temp_dir().join(format!("thin_main_loop_inotify_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let events = RefCell::new(vec!());
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_watch_path(&dir, WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVE | WatchMask::RECURSIVE, |e| {
+            events.borrow_mut().push(e);
+            true
+        }).unwrap();
+        let dir = &dir;
+        ml.call_asap(move || {
+            std::fs::write(dir.join("sub/a"), b"x").unwrap();
+            std::fs::create_dir(dir.join("new")).unwrap();
+        }).unwrap();
+        // The new directory must be watched before we touch anything in it
+        ml.call_after(Duration::from_millis(50), move || {
+            std::fs::rename(dir.join("sub/a"), dir.join("new/b")).unwrap();
+            std::fs::remove_file(dir.join("new/b")).unwrap();
+        }).unwrap();
+        ml.call_after(Duration::from_millis(100), crate::terminate).unwrap();
+        ml.run();
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(*events.borrow(), vec!(
+        FsEvent::Created(dir.join("sub/a")),
+        FsEvent::Created(dir.join("new")),
+        FsEvent::Moved { from: dir.join("sub/a"), to: dir.join("new/b") },
+        FsEvent::Deleted(dir.join("new/b")),
+    ));
+}
+
+#[test]
+fn moved_out_in_order() {
+    use std::cell::RefCell;
+    use crate::MainLoop;
+
+    let base = std::env::temp_dir().join(format!("thin_main_loop_inotify_out_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&base);
+    let dir = base.join("watched");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a"), b"x").unwrap();
+    let events = RefCell::new(vec!());
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_watch_path(&dir, WatchMask::CREATE | WatchMask::MOVE, |e| {
+            events.borrow_mut().push(e);
+            if events.borrow().len() == 2 { crate::terminate() }
+            true
+        }).unwrap();
+        let (base, dir) = (&base, &dir);
+        // In the same batch, so the move out must not be held back until after the create
+        ml.call_asap(move || {
+            std::fs::rename(dir.join("a"), base.join("a")).unwrap();
+            std::fs::write(dir.join("b"), b"x").unwrap();
+        }).unwrap();
+        ml.run();
+    }
+    std::fs::remove_dir_all(&base).unwrap();
+    assert_eq!(*events.borrow(), vec!(
+        FsEvent::MovedFrom(dir.join("a")),
+        FsEvent::Created(dir.join("b")),
+    ));
+}
@@ -0,0 +1,563 @@
+use crate::{CbKind, CbId, MainLoopError, IODirection, IOEvent, IOMode, dir_to_poll};
+use crate::mainloop::SendFnOnce;
+use crate::epoll_set::{EpollSet, EventFdSender, instant_to_monotonic};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::ptr;
+use std::time::Instant;
+
+// Kernel ABI, see linux/io_uring.h
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad2: [u64; 2],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+// time_t and c_long are 32 bits on some targets
+#[allow(clippy::unnecessary_cast)]
+fn instant_to_kernel(i: Instant) -> KernelTimespec {
+    let ts = instant_to_monotonic(i);
+    KernelTimespec { tv_sec: ts.tv_sec as i64, tv_nsec: ts.tv_nsec as i64 }
+}
+
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_POLL_REMOVE: u8 = 7;
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_OP_TIMEOUT_REMOVE: u8 = 12;
+const IORING_POLL_UPDATE_EVENTS: u32 = 1 << 1;
+const IORING_TIMEOUT_ABS: u32 = 1;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+const RING_ENTRIES: u32 = 256;
+
+// User data for the eventfd, CbIds are never this high.
+const EVENTFD_DATA: u64 = u64::MAX;
+// User data for the remove requests, whose completions we don't care about.
+const REMOVE_DATA: u64 = u64::MAX - 1;
+// Tags on the CbId in the user data of poll updates, and of the removes that replace them
+// on kernels without IORING_POLL_UPDATE_EVENTS.
+const UPDATE_DATA: u64 = 1 << 62;
+const READD_DATA: u64 = 1 << 61;
+
+fn last_error(what: &str) -> MainLoopError {
+    let e = io::Error::last_os_error();
+    MainLoopError::Other(io::Error::new(e.kind(), format!("{} failed: {}", what, e)).into())
+}
+
+fn res_to_event(res: i32) -> IOEvent {
+    if res < 0 { return IOEvent { error: true, ..Default::default() } }
+    IOEvent::from_poll(res as libc::c_short)
+}
+
+struct Mmap(*mut libc::c_void, usize);
+
+impl Mmap {
+    fn new(fd: RawFd, size: usize, offset: libc::off_t) -> Result<Self, MainLoopError> {
+        let p = unsafe { libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset) };
+        if p == libc::MAP_FAILED { return Err(last_error("mmap of io_uring")) }
+        Ok(Mmap(p, size))
+    }
+    unsafe fn at<T>(&self, offset: u32) -> *mut T { (self.0 as *mut u8).add(offset as usize) as *mut T }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) { unsafe { libc::munmap(self.0, self.1); } }
+}
+
+struct Ring {
+    // Field order matters: the mappings must go away before the fd is closed.
+    sq_ring: Mmap,
+    cq_ring: Option<Mmap>,
+    sqes: Mmap,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    to_submit: Cell<u32>,
+    fd: OwnedFd,
+}
+
+impl Ring {
+    fn new() -> Result<Self, MainLoopError> {
+        let mut p: Params = Default::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, RING_ENTRIES, &mut p as *mut Params) };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            return Err(MainLoopError::Other(io::Error::new(e.kind(), format!("io_uring is not available: {}", e)).into()));
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_size = p.sq_off.array as usize + p.sq_entries as usize * 4;
+        let cq_size = p.cq_off.cqes as usize + p.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let single = p.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let sq_ring = Mmap::new(fd.as_raw_fd(), if single { sq_size.max(cq_size) } else { sq_size }, IORING_OFF_SQ_RING)?;
+        let cq_ring = if single { None } else { Some(Mmap::new(fd.as_raw_fd(), cq_size, IORING_OFF_CQ_RING)?) };
+        let sqes = Mmap::new(fd.as_raw_fd(), p.sq_entries as usize * std::mem::size_of::<Sqe>(), IORING_OFF_SQES)?;
+        let ring = Ring { sq_ring, cq_ring, sqes, sq_off: p.sq_off, cq_off: p.cq_off, to_submit: Cell::new(0), fd };
+        // Use a one-to-one mapping between the SQ array and the SQEs.
+        for i in 0..p.sq_entries {
+            unsafe { *ring.sq_ring.at::<u32>(ring.sq_off.array).add(i as usize) = i; }
+        }
+        Ok(ring)
+    }
+
+    fn cq(&self) -> &Mmap { self.cq_ring.as_ref().unwrap_or(&self.sq_ring) }
+
+    unsafe fn atomic(m: &Mmap, offset: u32) -> &AtomicU32 { &*m.at::<AtomicU32>(offset) }
+
+    fn push_sqe(&self, sqe: Sqe) -> Result<(), MainLoopError> {
+        unsafe {
+            let entries = *self.sq_ring.at::<u32>(self.sq_off.ring_entries);
+            let mask = *self.sq_ring.at::<u32>(self.sq_off.ring_mask);
+            let tail = Self::atomic(&self.sq_ring, self.sq_off.tail).load(Ordering::Relaxed);
+            let head = Self::atomic(&self.sq_ring, self.sq_off.head).load(Ordering::Acquire);
+            if tail.wrapping_sub(head) >= entries {
+                // Submission queue is full, make room
+                self.enter(0)?;
+                return self.push_sqe(sqe);
+            }
+            *self.sqes.at::<Sqe>(0).add((tail & mask) as usize) = sqe;
+            Self::atomic(&self.sq_ring, self.sq_off.tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.to_submit.set(self.to_submit.get() + 1);
+        Ok(())
+    }
+
+    // Submits pending SQEs and (optionally) waits for completions, in one syscall.
+    fn enter(&self, min_complete: u32) -> Result<(), MainLoopError> {
+        let n = self.to_submit.get();
+        let r = unsafe { libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), n, min_complete,
+            IORING_ENTER_GETEVENTS, ptr::null::<libc::sigset_t>(), 0) };
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted { return Ok(()) }
+            return Err(last_error("io_uring_enter"));
+        }
+        self.to_submit.set(n - r as u32);
+        Ok(())
+    }
+
+    fn pop_cqe(&self) -> Option<(u64, i32)> {
+        let cq = self.cq();
+        unsafe {
+            let head = Self::atomic(cq, self.cq_off.head).load(Ordering::Relaxed);
+            let tail = Self::atomic(cq, self.cq_off.tail).load(Ordering::Acquire);
+            if head == tail { return None }
+            let mask = *cq.at::<u32>(self.cq_off.ring_mask);
+            let cqe = &*cq.at::<Cqe>(self.cq_off.cqes).add((head & mask) as usize);
+            let r = (cqe.user_data, cqe.res);
+            Self::atomic(cq, self.cq_off.head).store(head.wrapping_add(1), Ordering::Release);
+            Some(r)
+        }
+    }
+}
+
+enum Source {
+    Asap,
+    // The timespec must stay alive until the SQE is submitted.
+    Timer(Box<KernelTimespec>),
+    IO(RawFd, u32),
+    Multi(EpollSet),
+}
+
+struct Entry<'a> {
+    source: Source,
+    // False for one-shot IO callbacks, between a call and set_io_direction
+    armed: bool,
+    kind: Option<CbKind<'a>>,
+}
+
+pub struct Backend<'a> {
+    ring: Ring,
+    eventfd: Arc<OwnedFd>,
+    recv: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    cb_map: RefCell<HashMap<CbId, Entry<'a>>>,
+    asap: RefCell<VecDeque<CbId>>,
+    // Cleared when the kernel turns out not to support IORING_POLL_UPDATE_EVENTS (before 5.13)
+    poll_update: Cell<bool>,
+}
+
+impl<'a> Backend<'a> {
+    pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
+        let ring = Ring::new()?;
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if eventfd < 0 { return Err(last_error("eventfd")) }
+        let eventfd = Arc::new(unsafe { OwnedFd::from_raw_fd(eventfd) });
+
+        let (tx, rx) = channel();
+        let be = Backend {
+            ring: ring,
+            eventfd: eventfd.clone(),
+            recv: rx,
+            cb_map: Default::default(),
+            asap: Default::default(),
+            poll_update: Cell::new(true),
+        };
+        be.arm_eventfd()?;
+        Ok((be, Box::new(EventFdSender::new(eventfd, tx))))
+    }
+
+    fn arm_eventfd(&self) -> Result<(), MainLoopError> {
+        self.ring.push_sqe(Sqe { opcode: IORING_OP_POLL_ADD, fd: self.eventfd.as_raw_fd(),
+            op_flags: libc::POLLIN as u32, user_data: EVENTFD_DATA, ..Default::default() })
+    }
+
+    fn arm(&self, cbid: CbId, source: &Source) -> Result<(), MainLoopError> {
+        match source {
+            Source::Asap => Ok(()),
+            Source::Timer(ts) => self.ring.push_sqe(Sqe { opcode: IORING_OP_TIMEOUT, fd: -1,
+                addr: &**ts as *const KernelTimespec as u64, len: 1, op_flags: IORING_TIMEOUT_ABS, user_data: cbid.0, ..Default::default() }),
+            Source::IO(fd, events) => self.ring.push_sqe(Sqe { opcode: IORING_OP_POLL_ADD, fd: *fd,
+                op_flags: *events, user_data: cbid.0, ..Default::default() }),
+            Source::Multi(set) => self.ring.push_sqe(Sqe { opcode: IORING_OP_POLL_ADD, fd: set.as_raw_fd(),
+                op_flags: libc::POLLIN as u32, user_data: cbid.0, ..Default::default() }),
+        }
+    }
+
+    fn disarm(&self, cbid: CbId, source: &Source) {
+        let opcode = match source {
+            Source::Asap => return,
+            Source::Timer(_) => IORING_OP_TIMEOUT_REMOVE,
+            Source::IO(_, _) | Source::Multi(_) => IORING_OP_POLL_REMOVE,
+        };
+        // If this fails, the completion will be ignored later anyway
+        let _ = self.ring.push_sqe(Sqe { opcode: opcode, fd: -1, addr: cbid.0, user_data: REMOVE_DATA, ..Default::default() });
+    }
+
+    fn dispatch(&self, cbid: CbId, res: Option<i32>) {
+        let (kind, is_io, ready) = {
+            let mut map = self.cb_map.borrow_mut();
+            let entry = match map.get_mut(&cbid) {
+                Some(entry) => entry,
+                None => return,
+            };
+            let ready = match &entry.source {
+                Source::Multi(set) => match set.ready() {
+                    Some(ready) => Some(ready),
+                    None => {
+                        // Spurious wakeup, wait again
+                        if let Err(e) = self.arm(cbid, &entry.source) {
+                            map.remove(&cbid);
+                            drop(map);
+                            crate::mainloop::report_error(e);
+                        }
+                        return;
+                    }
+                },
+                _ => None,
+            };
+            (entry.kind.take(), matches!(entry.source, Source::IO(_, _)), ready)
+        };
+        let ev = if is_io { res.map(res_to_event) } else { None };
+        if let Some(mut kind) = kind {
+            let keep = match &ready {
+                Some(ready) => kind.call_multi(ready),
+                None => kind.call_mut(ev),
+            };
+            if keep {
+                let mut map = self.cb_map.borrow_mut();
+                if let Some(entry) = map.get_mut(&cbid) {
+                    let r = match &mut entry.source {
+                        Source::Multi(set) => set.update(&kind.multi_handles().unwrap(), kind.deadline()),
+                        // The timeout has completed, so the kernel is done with the timespec
+                        Source::Timer(ts) => { **ts = instant_to_kernel(kind.deadline().unwrap()); Ok(()) },
+                        _ => Ok(()),
+                    };
+                    if let Err(e) = r {
+                        // E g a handle that is closed, or a regular file, which epoll cannot watch
+                        map.remove(&cbid);
+                        drop(map);
+                        crate::mainloop::report_error(e);
+                        return;
+                    }
+                    entry.armed = kind.io_mode() != Some(IOMode::OneShot);
+                    entry.kind = Some(kind);
+                    // Both polls and timeouts are one-shot in io_uring, so level (and edge)
+                    // triggering is done by re-arming.
+                    if entry.armed {
+                        if let Err(e) = self.arm(cbid, &entry.source) {
+                            map.remove(&cbid);
+                            drop(map);
+                            crate::mainloop::report_error(e);
+                        }
+                    }
+                }
+                return;
+            }
+            self.cb_map.borrow_mut().remove(&cbid);
+            kind.post_call_mut();
+        } else {
+            // A previous call panicked
+            self.cb_map.borrow_mut().remove(&cbid);
+        }
+    }
+
+    // Removes the poll, so that it can be added again with new events once the removal has completed.
+    fn readd(&self, cbid: CbId) -> Result<(), MainLoopError> {
+        self.ring.push_sqe(Sqe { opcode: IORING_OP_POLL_REMOVE, fd: -1, addr: cbid.0, user_data: cbid.0 | READD_DATA, ..Default::default() })
+    }
+
+    fn update_done(&self, cbid: CbId, res: i32) {
+        // ENOENT: the poll has completed, and is re-armed with the new events after dispatch
+        if res >= 0 || res == -libc::ENOENT { return; }
+        self.poll_update.set(false);
+        if let Err(e) = self.readd(cbid) { crate::mainloop::report_error(e) }
+    }
+
+    fn readd_done(&self, cbid: CbId, res: i32) {
+        // If the poll had completed instead, it is re-armed after dispatch
+        if res < 0 { return; }
+        let map = self.cb_map.borrow();
+        if let Some(entry) = map.get(&cbid) {
+            if entry.armed {
+                if let Err(e) = self.arm(cbid, &entry.source) { crate::mainloop::report_error(e) }
+            }
+        }
+    }
+
+    fn run_thread_calls(&self) {
+        let mut buf = 0u64;
+        unsafe { libc::read(self.eventfd.as_raw_fd(), &mut buf as *mut _ as *mut libc::c_void, 8) };
+        // Without the poll, calls from other threads would never wake us up
+        if let Err(e) = self.arm_eventfd() { crate::mainloop::terminate_with_error(e) }
+        while let Ok(cb) = self.recv.try_recv() {
+            cb();
+        }
+    }
+}
+
+impl<'a> crate::mainloop::Backend<'a> for Backend<'a> {
+    fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+        let source = if let Some((handle, direction)) = cb.handle() {
+            Source::IO(handle.0, dir_to_poll(direction) as u32)
+        } else if let Some(handles) = cb.multi_handles() {
+            let mut set = EpollSet::new()?;
+            set.update(&handles, cb.deadline())?;
+            Source::Multi(set)
+        } else if let Some(d) = cb.deadline() {
+            Source::Timer(Box::new(instant_to_kernel(d)))
+        } else {
+            self.asap.borrow_mut().push_back(cbid);
+            Source::Asap
+        };
+        self.arm(cbid, &source)?;
+        self.cb_map.borrow_mut().insert(cbid, Entry { source: source, armed: true, kind: Some(cb) });
+        Ok(())
+    }
+
+    fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+        self.asap.borrow_mut().retain(|x| *x != cbid);
+        let entry = self.cb_map.borrow_mut().remove(&cbid)?;
+        self.disarm(cbid, &entry.source);
+        if let Source::Timer(_) = entry.source {
+            // Make sure the kernel has read the timespec before we free it
+            let _ = self.ring.enter(0);
+        }
+        entry.kind
+    }
+
+    fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        let mut map = self.cb_map.borrow_mut();
+        let entry = match map.get_mut(&cbid) {
+            Some(entry) => entry,
+            None => return Err(crate::mainloop::not_an_io_callback(cbid)),
+        };
+        let events = match &mut entry.source {
+            Source::IO(_, events) => events,
+            _ => return Err(crate::mainloop::not_an_io_callback(cbid)),
+        };
+        *events = dir_to_poll(dir) as u32;
+        if !entry.armed {
+            entry.armed = true;
+            return self.arm(cbid, &entry.source);
+        }
+        // Updates the poll in place (Linux 5.13+). If the poll has completed already,
+        // this fails, and the new events are used when the poll is re-armed after dispatch.
+        if self.poll_update.get() {
+            self.ring.push_sqe(Sqe { opcode: IORING_OP_POLL_REMOVE, fd: -1, addr: cbid.0,
+                len: IORING_POLL_UPDATE_EVENTS, op_flags: *events, user_data: cbid.0 | UPDATE_DATA, ..Default::default() })
+        } else {
+            self.readd(cbid)
+        }
+    }
+
+    fn run_one(&self, wait: bool) -> bool {
+        let asap = self.asap.borrow_mut().pop_front();
+        let min_complete = if wait && asap.is_none() { 1 } else { 0 };
+        if let Err(e) = self.ring.enter(min_complete) {
+            crate::mainloop::terminate_with_error(e);
+            return false;
+        }
+
+        let mut called = false;
+        while let Some((data, res)) = self.ring.pop_cqe() {
+            match data {
+                EVENTFD_DATA => self.run_thread_calls(),
+                REMOVE_DATA => {},
+                _ if data & UPDATE_DATA != 0 => self.update_done(CbId(data & !UPDATE_DATA), res),
+                _ if data & READD_DATA != 0 => self.readd_done(CbId(data & !READD_DATA), res),
+                // Cancelled by us
+                _ if res == -libc::ECANCELED => {},
+                _ => {
+                    called = true;
+                    self.dispatch(CbId(data), Some(res));
+                }
+            }
+        }
+
+        if let Some(cbid) = asap {
+            self.dispatch(cbid, None);
+            return true;
+        }
+        called
+    }
+}
+
+#[test]
+fn unavailable_or_working() {
+    use std::os::unix::net::UnixStream;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use crate::mainloop::Backend as _;
+
+    let (be, _) = match Backend::new() {
+        Ok(x) => x,
+        Err(e) => {
+            // E g, the kernel is too old, or io_uring is disabled by seccomp
+            let s = format!("{:?}", e);
+            assert!(s.contains("io_uring is not available"), "{}", s);
+            return;
+        }
+    };
+    let (a, mut b) = UnixStream::pair().unwrap();
+    let fired = Rc::new(Cell::new(0));
+    let f1 = fired.clone();
+    let f2 = fired.clone();
+    be.push(CbId(1), CbKind::io(crate::IOReader { io: a, f: move |_: &mut UnixStream, r| {
+        assert!(r.readable);
+        f1.set(f1.get() + 1);
+    }})).unwrap();
+    be.push(CbId(2), CbKind::after(move || {
+        b.write_all(b"x").unwrap();
+        f2.set(f2.get() + 10);
+    }, Duration::from_millis(20))).unwrap();
+    while fired.get() < 11 { be.run_one(true); }
+    assert!(be.cancel(CbId(1)).is_some());
+    assert!(be.cancel(CbId(2)).is_none());
+}
+
+#[test]
+fn io_direction_fallback() {
+    use std::os::unix::net::UnixStream;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use crate::{CbHandle, IOAble};
+    use crate::mainloop::Backend as _;
+
+    struct Writable(UnixStream, Rc<Cell<bool>>);
+    impl IOAble for Writable {
+        fn handle(&self) -> CbHandle { CbHandle(self.0.as_raw_fd()) }
+        fn direction(&self) -> IODirection { IODirection::Read }
+        fn on_rw(&mut self, r: IOEvent) -> bool {
+            assert!(r.writable);
+            self.1.set(true);
+            false
+        }
+    }
+
+    // Both with poll updates, and with the remove and re-add done for kernels before 5.13
+    for update in [true, false] {
+        let (be, _) = match Backend::new() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        be.poll_update.set(update);
+        let (a, _b) = UnixStream::pair().unwrap();
+        let (fired, timed_out) = (Rc::new(Cell::new(false)), Rc::new(Cell::new(false)));
+        be.push(CbId(1), CbKind::io(Writable(a, fired.clone()))).unwrap();
+        let t = timed_out.clone();
+        be.push(CbId(2), CbKind::after(move || t.set(true), Duration::from_secs(5))).unwrap();
+        be.run_one(false);
+        be.set_io_direction(CbId(1), IODirection::Write).unwrap();
+        while !fired.get() && !timed_out.get() { be.run_one(true); }
+        assert!(fired.get(), "poll_update: {}", update);
+        be.cancel(CbId(2));
+    }
+}
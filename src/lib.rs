@@ -6,6 +6,8 @@
 #![allow(unused_variables)]
 // #![allow(unused_imports)]
 #![allow(dead_code)]
+// Field initializers are spelled out, e g `id: id`
+#![allow(clippy::redundant_field_names)]
 
 
 #[macro_use]
@@ -20,16 +22,50 @@ mod winmsg;
 #[cfg(feature = "web")]
 mod web;
 
-#[cfg(not(any(feature = "win32", feature = "glib", feature = "web")))]
+#[cfg(all(feature = "epoll", target_os = "linux"))]
+mod epoll;
+
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod io_uring;
+
+#[cfg(all(any(feature = "epoll", feature = "io_uring"), target_os = "linux"))]
+mod epoll_set;
+
+#[cfg(not(feature = "web"))]
 mod ruststd;
 
 #[cfg(not(feature = "web"))]
 mod mainloop;
 
+mod writer;
+
+pub mod framed;
+
+#[cfg(all(unix, not(feature = "web")))]
+mod signal;
+
+#[cfg(all(unix, not(feature = "web")))]
+pub use crate::signal::Signal;
+
+#[cfg(all(unix, not(feature = "web")))]
+mod child;
+
+#[cfg(all(target_os = "linux", not(feature = "web")))]
+mod inotify;
+
+#[cfg(all(unix, not(feature = "web")))]
+pub mod calendar;
+
+#[cfg(all(target_os = "linux", not(feature = "web")))]
+pub use crate::inotify::{FsEvent, WatchMask};
+
+pub use crate::writer::{IOWriter, WriteQueue};
+pub use crate::framed::{Decoder, FrameReader, LineReader};
+
 #[cfg(not(feature = "web"))]
-pub use crate::mainloop::MainLoop;
+pub use crate::mainloop::{MainLoop, BackendKind, BACKEND_ENV_VAR, Backend, SendFnOnce, ffi_cb_wrapper};
 
-use std::time::Duration;
+use std::time::{Instant, Duration};
 use std::thread::ThreadId;
 
 /// Possible error codes returned from the main loop API.
@@ -42,10 +78,51 @@ pub enum MainLoopError {
     Other(Box<dyn std::error::Error>),
 }
 
+impl std::fmt::Display for MainLoopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MainLoopError::TooManyMainLoops => write!(f, "There is already a main loop on this thread"),
+            MainLoopError::NoMainLoop => write!(f, "There is no main loop on this thread"),
+            MainLoopError::Unsupported => write!(f, "Not supported by the main loop backend"),
+            MainLoopError::DurationTooLong => write!(f, "Duration is too long for the main loop backend"),
+            MainLoopError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MainLoopError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MainLoopError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<MainLoopError> for std::io::Error {
+    fn from(e: MainLoopError) -> Self {
+        match e {
+            // Most of these come from a failed syscall, keep its ErrorKind
+            MainLoopError::Other(e) => match e.downcast::<std::io::Error>() {
+                Ok(e) => *e,
+                Err(e) => std::io::Error::other(e.to_string()),
+            },
+            e => std::io::Error::other(e.to_string()),
+        }
+    }
+}
+
 /// Callback Id, can be used to cancel callback before its run.
 #[derive(Clone, Debug, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct CbId(u64);
 
+impl CbId {
+    /// The numeric value of the id, e g for passing as user data to a poller.
+    ///
+    /// Ids are never zero, and are unique within a thread, also across main loops.
+    pub fn as_u64(&self) -> u64 { self.0 }
+}
+
 /// Abstraction around unix fds and windows sockets.
 #[cfg(windows)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash)]
@@ -72,56 +149,148 @@ struct CbFuture<'a> {
 }
 */
 
-enum CbKind<'a> {
+/// A callback, as handed to a Backend.
+#[non_exhaustive]
+pub enum CbKind<'a> {
     Asap(Box<dyn FnOnce() + 'a>),
-    After(Box<dyn FnOnce() + 'a>, Duration),
-    Interval(Box<dyn FnMut() -> bool + 'a>, Duration),
+    /// Called once, at the Instant.
+    At(Box<dyn FnOnce() + 'a>, Instant),
+    /// Called every period, next time at the Instant. The Instant is advanced by `call_mut`.
+    Interval(Box<dyn FnMut() -> bool + 'a>, Duration, Instant),
     IO(Box<dyn IOAble + 'a>),
+    MultiIO(Box<dyn MultiIOAble + 'a>),
+    Source(Box<dyn EventSource + 'a>),
+    /// Called once, when the child process has exited.
+    #[cfg(unix)]
+    ChildWatch(Box<dyn FnOnce(std::process::ExitStatus) + 'a>, libc::pid_t),
 //    Future(CbFuture<'a>),
 }
 
+fn millis_ceil(d: Duration) -> Result<u32, MainLoopError> {
+    let m = (u32::MAX / 1000) - 2;
+    let s = d.as_secs();
+    if s >= m as u64 { return Err(MainLoopError::DurationTooLong) }
+    Ok((s as u32) * 1000 + d.subsec_nanos().div_ceil(1_000_000))
+}
+
 impl<'a> CbKind<'a> {
     // Constructors
     pub fn asap<F: FnOnce() + 'a>(f: F) -> Self { CbKind::Asap(Box::new(f)) }
-    pub fn after<F: FnOnce() + 'a>(f: F, d: Duration) -> Self { CbKind::After(Box::new(f), d) }
-    pub fn interval<F: FnMut() -> bool + 'a>(f: F, d: Duration) -> Self { CbKind::Interval(Box::new(f), d) }
+    pub fn after<F: FnOnce() + 'a>(f: F, d: Duration) -> Self { CbKind::At(Box::new(f), Instant::now() + d) }
+    pub fn at<F: FnOnce() + 'a>(f: F, i: Instant) -> Self { CbKind::At(Box::new(f), i) }
+    pub fn interval<F: FnMut() -> bool + 'a>(f: F, d: Duration) -> Self { CbKind::Interval(Box::new(f), d, Instant::now() + d) }
     pub fn io<IO: IOAble + 'a>(io: IO) -> Self { CbKind::IO(Box::new(io)) }
+    pub fn multi_io<IO: MultiIOAble + 'a>(io: IO) -> Self { CbKind::MultiIO(Box::new(io)) }
+    pub fn source<S: EventSource + 'a>(s: S) -> Self { CbKind::Source(Box::new(s)) }
+    #[cfg(unix)]
+    pub fn child_watch<F: FnOnce(std::process::ExitStatus) + 'a>(f: F, pid: libc::pid_t) -> Self { CbKind::ChildWatch(Box::new(f), pid) }
 
-    // Used to figure out which one it is
+    /// For timers, the time left until the callback is due. Zero if it is overdue.
     pub fn duration(&self) -> Option<Duration> {
         match self {
             CbKind::IO(_) => None,
+            CbKind::MultiIO(_) => None,
+            CbKind::Source(_) => None,
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => None,
             CbKind::Asap(_) => None,
-            CbKind::After(_, d) => Some(*d),
-            CbKind::Interval(_, d) => Some(*d),
+            CbKind::At(_, i) => Some(i.saturating_duration_since(Instant::now())),
+            CbKind::Interval(_, _, i) => Some(i.saturating_duration_since(Instant::now())),
 //            CbKind::Future(f) => f.instant.map(|x| x - Instant::now()),
         }
     }
+
+    /// The period of Interval callbacks.
+    pub fn period(&self) -> Option<Duration> {
+        match self {
+            CbKind::Interval(_, d, _) => Some(*d),
+            _ => None,
+        }
+    }
+    /// The duration in whole milliseconds, for backends that cannot do better.
+    ///
+    /// Rounded upwards, so the callback is never called early.
     pub fn duration_millis(&self) -> Result<Option<u32>, MainLoopError> {
-        if let Some(d) = self.duration() {
-            let m = (u32::max_value() / 1000) - 1;
-            let s = d.as_secs();
-            if s >= m as u64 { return Err(MainLoopError::DurationTooLong) }
-            Ok(Some((s as u32) * 1000 + d.subsec_millis()))
-        } else { Ok(None) } 
+        self.duration().map(millis_ceil).transpose()
+    }
+
+    /// The period of Interval callbacks in whole milliseconds, rounded upwards.
+    pub fn period_millis(&self) -> Result<Option<u32>, MainLoopError> {
+        self.period().map(millis_ceil).transpose()
     }
 
     pub fn handle(&self) -> Option<(CbHandle, IODirection)> {
         match self {
             CbKind::IO(io) => Some((io.handle(), io.direction())),
+            CbKind::MultiIO(_) => None,
+            CbKind::Source(_) => None,
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => None,
             CbKind::Asap(_) => None,
-            CbKind::After(_, _) => None,
-            CbKind::Interval(_, _) => None,
+            CbKind::At(_, _) => None,
+            CbKind::Interval(_, _, _) => None,
 //            CbKind::Future(f) => f.handle,
         }
     }
 
-    // If "false" is returned, please continue with making a call to post_call_mut.
-    pub (crate) fn call_mut(&mut self, io_dir: Option<Result<IODirection, std::io::Error>>) -> bool {
+    pub fn io_mode(&self) -> Option<IOMode> {
+        match self {
+            CbKind::IO(io) => Some(io.mode()),
+            CbKind::MultiIO(_) => None,
+            CbKind::Source(_) => None,
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => None,
+            CbKind::Asap(_) => None,
+            CbKind::At(_, _) => None,
+            CbKind::Interval(_, _, _) => None,
+        }
+    }
+
+    /// The handles of a MultiIO callback. Backends should query this again after every call.
+    pub fn multi_handles(&self) -> Option<Vec<(CbHandle, IODirection)>> {
+        match self {
+            CbKind::MultiIO(io) => Some(io.handles()),
+            CbKind::IO(_) => None,
+            CbKind::Source(_) => None,
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => None,
+            CbKind::Asap(_) => None,
+            CbKind::At(_, _) => None,
+            CbKind::Interval(_, _, _) => None,
+        }
+    }
+
+    /// When a timer is due, or when a MultiIO callback should be called even if none of its handles are ready.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self {
+            CbKind::MultiIO(io) => io.deadline(),
+            CbKind::IO(_) => None,
+            CbKind::Source(_) => None,
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => None,
+            CbKind::Asap(_) => None,
+            CbKind::At(_, i) => Some(*i),
+            CbKind::Interval(_, _, i) => Some(*i),
+        }
+    }
+
+    /// Calls the callback.
+    ///
+    /// For IO callbacks, `io_ev` must be set to the I/O readiness of the handle.
+    /// If "false" is returned, please continue with making a call to post_call_mut.
+    pub fn call_mut(&mut self, io_ev: Option<IOEvent>) -> bool {
         match self {
-            CbKind::Interval(f, _) => f(),
-            CbKind::IO(io) => io.on_rw(io_dir.unwrap()),
-            CbKind::After(_, _) => false,
+            CbKind::Interval(f, d, i) => {
+                // Counted from the previous deadline rather than from now, so intervals do not drift
+                *i += *d;
+                f()
+            },
+            CbKind::IO(io) => io.on_rw(io_ev.unwrap()),
+            CbKind::MultiIO(io) => io.on_ready(&[]),
+            CbKind::Source(s) => s.dispatch(),
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => false,
+            CbKind::At(_, _) => false,
             CbKind::Asap(_) => false,
 /*            CbKind::Future(f) => {
                 #[cfg(feature = "futures")]
@@ -134,18 +303,58 @@ impl<'a> CbKind<'a> {
         }
     }
 
-    pub (crate) fn post_call_mut(self) {
+    /// Tells an IO callback its CbId. Called by the main loop before the callback is pushed.
+    pub fn registered(&mut self, id: CbId) {
+        if let CbKind::IO(io) = self { io.registered(id) }
+    }
+
+    /// Calls a MultiIO callback with its ready handles.
+    ///
+    /// For other callbacks, this is the same as `call_mut(None)`.
+    pub fn call_multi(&mut self, ready: &[(CbHandle, IOEvent)]) -> bool {
         match self {
-            CbKind::After(f, _) => f(),
+            CbKind::MultiIO(io) => io.on_ready(ready),
+            _ => self.call_mut(None),
+        }
+    }
+
+    /// Finishes the callback, e g calls the FnOnce of Asap and At callbacks.
+    pub fn post_call_mut(self) {
+        match self {
+            CbKind::At(f, _) => f(),
             CbKind::Asap(f) => f(),
-            CbKind::Interval(_, _) => {},
+            CbKind::Interval(_, _, _) => {},
             CbKind::IO(_) => {},
+            CbKind::MultiIO(_) => {},
+            CbKind::Source(_) => {},
+            #[cfg(unix)]
+            CbKind::ChildWatch(_, _) => {},
 //            CbKind::Future(_) => {},
         }
     }
+
+    /// The process id of ChildWatch callbacks.
+    #[cfg(unix)]
+    pub fn child_pid(&self) -> Option<libc::pid_t> {
+        match self {
+            CbKind::ChildWatch(_, pid) => Some(*pid),
+            _ => None,
+        }
+    }
+
+    /// Finishes a ChildWatch callback, i e calls its FnOnce with the exit status.
+    ///
+    /// For other callbacks, this is the same as `post_call_mut`.
+    #[cfg(unix)]
+    pub fn post_call_child(self, status: std::process::ExitStatus) {
+        match self {
+            CbKind::ChildWatch(f, _) => f(status),
+            _ => self.post_call_mut(),
+        }
+    }
 }
 
-fn call_internal(cb: CbKind<'static>) -> Result<(), MainLoopError> { 
+fn call_internal(cb: CbKind<'static>) -> Result<CbId, MainLoopError> { 
     #[cfg(not(feature = "web"))]
     let r = mainloop::call_internal(cb);
 
@@ -162,7 +371,7 @@ fn call_internal(cb: CbKind<'static>) -> Result<(), MainLoopError> {
 /// * node.js: process.nextTick
 /// * web: Promise.resolve().then(...)
 /// * win32: PostMessage
-pub fn call_asap<F: FnOnce() + 'static>(f: F) -> Result<(), MainLoopError> {
+pub fn call_asap<F: FnOnce() + 'static>(f: F) -> Result<CbId, MainLoopError> {
     let cb = CbKind::asap(f);
     call_internal(cb)
 }
@@ -174,11 +383,40 @@ pub fn call_asap<F: FnOnce() + 'static>(f: F) -> Result<(), MainLoopError> {
 /// * node.js: setTimeout
 /// * web: window.setTimeout
 /// * win32: SetTimer
-pub fn call_after<F: FnOnce() + 'static>(d: Duration, f: F) -> Result<(), MainLoopError> {
+pub fn call_after<F: FnOnce() + 'static>(d: Duration, f: F) -> Result<CbId, MainLoopError> {
     let cb = CbKind::after(f, d);
     call_internal(cb)
 }
 
+/// Runs a function once, at a specified instant.
+///
+/// If the instant has already passed, the function runs as soon as possible.
+/// Useful for drift-free chains of timers, where each deadline is computed from the previous one.
+pub fn call_at<F: FnOnce() + 'static>(i: Instant, f: F) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::at(f, i);
+    call_internal(cb)
+}
+
+/// Runs a function once, when the wall clock reaches a specified time.
+///
+/// Unlike `call_after` and `call_at`, this follows changes to the system clock.
+/// If the time has already passed, the function runs as soon as possible.
+#[cfg(all(unix, not(feature = "web")))]
+pub fn call_at_time<F: FnOnce() + 'static>(t: std::time::SystemTime, f: F) -> Result<CbId, MainLoopError> {
+    let mut f = Some(f);
+    call_multi_io(calendar::WallTimer::new(t, move |_| { if let Some(f) = f.take() { f() }; false })?)
+}
+
+/// Runs a function according to a wall clock schedule, e g `calendar::Daily` or `calendar::Cron`.
+///
+/// The function gets the time it was scheduled for. Return "true" from the function to continue
+/// running or "false" to remove the callback from the main loop.
+#[cfg(all(unix, not(feature = "web")))]
+pub fn call_schedule<S, F>(s: S, f: F) -> Result<CbId, MainLoopError>
+where S: calendar::Schedule + 'static, F: FnMut(std::time::SystemTime) -> bool + 'static {
+    call_multi_io(calendar::WallTimer::new(s, f)?)
+}
+
 /// Runs a function at regular intervals
 ///
 /// Return "true" from the function to continue running or "false" to
@@ -189,7 +427,7 @@ pub fn call_after<F: FnOnce() + 'static>(d: Duration, f: F) -> Result<(), MainLo
 /// * node.js: setInterval
 /// * web: window.setInterval
 /// * win32: SetTimer
-pub fn call_interval<F: FnMut() -> bool + 'static>(d: Duration, f: F) -> Result<(), MainLoopError> {
+pub fn call_interval<F: FnMut() -> bool + 'static>(d: Duration, f: F) -> Result<CbId, MainLoopError> {
     let cb = CbKind::interval(f, d);
     call_internal(cb)
 }
@@ -209,19 +447,148 @@ pub enum IODirection {
     Both,
 }
 
+/// The readiness of a CbHandle, as reported to `IOAble::on_rw`.
+///
+/// Several flags can be set at the same time, e g a socket can be both readable
+/// and hung up when the other end has closed the connection, but there is still
+/// data left to read.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct IOEvent {
+    /// The handle can be read from without blocking.
+    pub readable: bool,
+    /// The handle can be written to without blocking.
+    pub writable: bool,
+    /// The other end has closed the connection.
+    pub hangup: bool,
+    /// An error condition is pending on the handle, or the handle is invalid.
+    pub error: bool,
+    /// Urgent (out-of-band) data is available for reading.
+    pub priority: bool,
+}
+
+impl IOEvent {
+    /// The readable and writable flags, as an IODirection.
+    pub fn direction(&self) -> IODirection {
+        match (self.readable, self.writable) {
+            (false, false) => IODirection::None,
+            (true, false) => IODirection::Read,
+            (false, true) => IODirection::Write,
+            (true, true) => IODirection::Both,
+        }
+    }
+
+    // Converts the revents of a pollfd
+    #[cfg(unix)]
+    pub (crate) fn from_poll(revents: libc::c_short) -> Self {
+        IOEvent {
+            readable: revents & libc::POLLIN != 0,
+            writable: revents & libc::POLLOUT != 0,
+            hangup: revents & (libc::POLLHUP | POLLRDHUP) != 0,
+            error: revents & (libc::POLLERR | libc::POLLNVAL) != 0,
+            priority: revents & libc::POLLPRI != 0,
+        }
+    }
+}
+
+impl From<IODirection> for IOEvent {
+    fn from(d: IODirection) -> Self {
+        IOEvent {
+            readable: d == IODirection::Read || d == IODirection::Both,
+            writable: d == IODirection::Write || d == IODirection::Both,
+            ..Default::default()
+        }
+    }
+}
+
+// Poll events to wait for, hangup and error conditions are always reported.
+#[cfg(unix)]
+pub (crate) fn dir_to_poll(d: IODirection) -> libc::c_short {
+    match d {
+        IODirection::None => 0,
+        IODirection::Read => libc::POLLIN | libc::POLLPRI | POLLRDHUP,
+        IODirection::Write => libc::POLLOUT,
+        IODirection::Both => libc::POLLIN | libc::POLLPRI | POLLRDHUP | libc::POLLOUT,
+    }
+}
+
+#[cfg(all(unix, target_os = "linux"))]
+const POLLRDHUP: libc::c_short = libc::POLLRDHUP;
+#[cfg(all(unix, not(target_os = "linux")))]
+const POLLRDHUP: libc::c_short = 0;
+
+/// Selects when an IO callback is called.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum IOMode {
+    /// Called for as long as the handle is ready. This is the default.
+    Level,
+    /// Called when the handle becomes ready. The callback should read or write until
+    /// it gets WouldBlock, or it might not be called again.
+    ///
+    /// Backends without native support treat this as Level, which results in extra calls only.
+    Edge,
+    /// Called once, then disabled until re-armed with `set_io_direction`.
+    OneShot,
+}
+
 /// Represents an object that can be read from and/or written to.
 pub trait IOAble {
     fn handle(&self) -> CbHandle;
 
     fn direction(&self) -> IODirection;
 
-    fn on_rw(&mut self, _: Result<IODirection, std::io::Error>) -> bool;
-    /* TODO: Handle Errors / hangup / etc */
+    /// How readiness is reported, see IOMode. Only queried when the object is added to the main loop.
+    fn mode(&self) -> IOMode { IOMode::Level }
+
+    /// Called with the object's CbId when it is added to a main loop, before any other call.
+    ///
+    /// This is useful e g for calling `set_io_direction` from the object itself.
+    fn registered(&mut self, _: CbId) {}
+
+    /// Called when the handle is ready, or a hangup or error condition occurs.
+    ///
+    /// Return false to remove the object from the main loop.
+    fn on_rw(&mut self, _: IOEvent) -> bool;
+}
+
+/// An object that watches several handles at once, and optionally a deadline.
+///
+/// This fits libraries that expose a set of pollfds plus a timeout, such as ALSA,
+/// libusb, XCB or libcurl's multi interface.
+pub trait MultiIOAble {
+    /// The handles to watch. This is queried again after every call to `on_ready`.
+    fn handles(&self) -> Vec<(CbHandle, IODirection)>;
+
+    /// If set, `on_ready` is called at this time even if no handle is ready.
+    /// This is queried again after every call to `on_ready`.
+    fn deadline(&self) -> Option<Instant> { None }
+
+    /// Called with all handles that are ready, or with an empty slice when the deadline has passed.
+    ///
+    /// Return false to remove the object from the main loop.
+    fn on_ready(&mut self, ready: &[(CbHandle, IOEvent)]) -> bool;
+}
+
+/// A custom event source, modelled on GLib's GSourceFuncs.
+///
+/// In every iteration of the main loop, `prepare` is called before waiting for events
+/// and `check` after waiting; if `check` returns true, `dispatch` is called.
+/// Use `call_thread` or another callback to wake the main loop up if the source
+/// becomes ready some other way than through its timeout.
+pub trait EventSource {
+    /// Called before the main loop waits. Returns the longest time the main loop
+    /// may wait before `check` is called, or None to wait for other events only.
+    fn prepare(&mut self) -> Option<Duration>;
+
+    /// Called after the main loop has waited. Returns true if `dispatch` should be called.
+    fn check(&mut self) -> bool;
+
+    /// Handles the event. Return false to remove the source from the main loop.
+    fn dispatch(&mut self) -> bool;
 }
 
 /// The most common I/O object is one from which you can read asynchronously.
 /// This is a simple convenience wrapper for that kind of I/O object.
-pub struct IOReader<IO, F: FnMut(&mut IO, Result<IODirection, std::io::Error>)>{
+pub struct IOReader<IO, F: FnMut(&mut IO, IOEvent)>{
     pub io: IO,
     pub f: F,
 }
@@ -229,12 +596,12 @@ pub struct IOReader<IO, F: FnMut(&mut IO, Result<IODirection, std::io::Error>)>{
 #[cfg(unix)]
 impl<IO, F> IOAble for IOReader<IO, F>
 where IO: std::os::unix::io::AsRawFd,
-      F: FnMut(&mut IO, Result<IODirection, std::io::Error>)
+      F: FnMut(&mut IO, IOEvent)
 {
     fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_fd()) }
 
     fn direction(&self) -> IODirection { IODirection::Read }
-    fn on_rw(&mut self, r: Result<IODirection, std::io::Error>) -> bool {
+    fn on_rw(&mut self, r: IOEvent) -> bool {
         (self.f)(&mut self.io, r);
         true
     }
@@ -243,23 +610,95 @@ where IO: std::os::unix::io::AsRawFd,
 #[cfg(windows)]
 impl<IO, F> IOAble for IOReader<IO, F>
 where IO: std::os::windows::io::AsRawSocket,
-      F: FnMut(&mut IO, Result<IODirection, std::io::Error>)
+      F: FnMut(&mut IO, IOEvent)
 {
     fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_socket()) }
 
     fn direction(&self) -> IODirection { IODirection::Read }
-    fn on_rw(&mut self, r: Result<IODirection, std::io::Error>) -> bool {
+    fn on_rw(&mut self, r: IOEvent) -> bool {
         (self.f)(&mut self.io, r);
         true
     }
 }
 
 /// Calls IOAble's callbacks when there is data to be read or written.
-pub fn call_io<IO: IOAble + 'static>(io: IO) -> Result<(), MainLoopError> {
+pub fn call_io<IO: IOAble + 'static>(io: IO) -> Result<CbId, MainLoopError> {
     let cb = CbKind::io(io);
     call_internal(cb)
 }
 
+/// Calls MultiIOAble's callback when any of its handles are ready, or its deadline has passed.
+pub fn call_multi_io<IO: MultiIOAble + 'static>(io: IO) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::multi_io(io);
+    call_internal(cb)
+}
+
+/// Calls the callback when the process receives a signal, e g libc::SIGINT.
+///
+/// The callback runs on the main loop like any other callback, so it is not restricted
+/// to async-signal-safe functions. Return false to stop watching for the signal, which
+/// restores the previous signal handler if this was the last watch for that signal.
+#[cfg(all(unix, not(feature = "web")))]
+pub fn call_signal<F: FnMut(Signal) -> bool + 'static>(signum: libc::c_int, f: F) -> Result<CbId, MainLoopError> {
+    call_io(signal::SignalWatch::new(signum, f)?)
+}
+
+/// Calls the callback when a child process has exited.
+///
+/// The child is reaped by the main loop, so do not wait for it elsewhere; if you do,
+/// the callback might never be called.
+///
+/// Corresponding platform specific APIs:
+/// * glib: g_child_watch_add
+#[cfg(all(unix, not(feature = "web")))]
+pub fn call_child_watch<F: FnOnce(std::process::ExitStatus) + 'static>(pid: libc::pid_t, f: F) -> Result<CbId, MainLoopError> {
+    child::check_pid(pid)?;
+    call_internal(CbKind::child_watch(f, pid))
+}
+
+/// Calls the callback when a file or directory changes on disk.
+///
+/// Return false from the callback to stop watching.
+#[cfg(all(target_os = "linux", not(feature = "web")))]
+pub fn call_watch_path<P: AsRef<std::path::Path>, F: FnMut(FsEvent) -> bool + 'static>(path: P, mask: WatchMask, f: F) -> Result<CbId, MainLoopError> {
+    call_io(inotify::PathWatch::new(path.as_ref(), mask, f)?)
+}
+
+/// Adds a custom event source to the main loop.
+///
+/// The source can be removed with `cancel`, or by returning false from `dispatch`.
+pub fn add_source<S: EventSource + 'static>(s: S) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::source(s);
+    call_internal(cb)
+}
+
+/// Cancels a callback before it is run.
+///
+/// Cancellation takes effect the next time the main loop runs: a callback
+/// that is already due in the current iteration might still be called.
+pub fn cancel(cbid: CbId) -> Result<(), MainLoopError> {
+    #[cfg(not(feature = "web"))]
+    let r = mainloop::cancel(cbid);
+
+    #[cfg(feature = "web")]
+    let r = Err(MainLoopError::Unsupported);
+    r
+}
+
+/// Changes whether an IO callback waits for reading, writing, or both.
+///
+/// Like `cancel`, this takes effect the next time the main loop runs.
+/// The callback keeps its CbId and state, which is useful e g for starting to
+/// wait for writability when there is outgoing data queued.
+pub fn set_io_direction(cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+    #[cfg(not(feature = "web"))]
+    let r = mainloop::set_io_direction(cbid, dir);
+
+    #[cfg(feature = "web")]
+    let r = Err(MainLoopError::Unsupported);
+    r
+}
+
 /// Terminates the currently running main loop.
 ///
 /// This function does nothing if the main loop is not running.
@@ -1,29 +1,24 @@
-#[cfg(feature = "glib")]
-use crate::glib::Backend;
-
-#[cfg(feature = "win32")]
-use crate::winmsg::Backend;
-
-#[cfg(not(any(feature = "win32", feature = "glib")))]
-use crate::ruststd::Backend;
-
 use std::cell::{Cell, RefCell};
 use std::marker::PhantomData;
 use std::rc::Rc;
-use std::panic;
+use std::{mem, panic};
 use std::any::Any;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::Mutex;
 use std::collections::HashMap;
 use std::thread::ThreadId;
-use crate::{CbKind, CbId, MainLoopError, IOAble};
+use crate::{CbKind, CbId, MainLoopError, IOAble, MultiIOAble, EventSource, IODirection};
 
 #[derive(Default)]
 struct MlTls {
     exists: Cell<bool>,
     terminated: Cell<bool>,
     running: Cell<bool>,
-    in_queue: RefCell<Vec<CbKind<'static>>>,
+    next_id: Cell<u64>,
+    in_queue: RefCell<Vec<(CbId, CbKind<'static>)>>,
+    cancel_queue: RefCell<Vec<CbId>>,
+    io_dir_queue: RefCell<Vec<(CbId, IODirection)>>,
+    errors: RefCell<Vec<MainLoopError>>,
     current_panic: RefCell<Option<Box<dyn Any + Send + 'static>>>,
 }
 
@@ -33,7 +28,12 @@ thread_local! {
     static ML_TLS: MlTls = Default::default();
 }
 
-pub (crate) fn ffi_cb_wrapper<R, F: FnOnce() -> R>(f: F, on_panic: R) -> R {
+/// Catches panics in callbacks called from FFI code.
+///
+/// Backends that call back into Rust from C should wrap the call in this function.
+/// On panic, `on_panic` is returned to the C code and the panic is resumed
+/// when the backend's `run_one` returns.
+pub fn ffi_cb_wrapper<R, F: FnOnce() -> R>(f: F, on_panic: R) -> R {
     match panic::catch_unwind(panic::AssertUnwindSafe(|| { f() })) {
         Ok(x) => x,
         Err(e) => {
@@ -49,10 +49,152 @@ pub (crate) fn ffi_cb_wrapper<R, F: FnOnce() -> R>(f: F, on_panic: R) -> R {
 
 // Thread sends
 
-pub (crate) trait SendFnOnce: Send {
+/// Sends functions to a main loop running on another thread.
+///
+/// `send` must wake the main loop up if it is waiting, and the function must be
+/// called on the main loop's thread.
+pub trait SendFnOnce: Send {
     fn send(&self, f: Box<dyn FnOnce() + Send + 'static>) -> Result<(), MainLoopError>;
 }
 
+// Backends
+
+/// The interface between a MainLoop and the event loop it runs on.
+///
+/// Implement this (together with SendFnOnce) to run a MainLoop on top of a
+/// poller or event loop not included in this crate, and use
+/// `MainLoop::with_custom_backend` to create the main loop.
+pub trait Backend<'a> {
+    /// Schedules a callback.
+    ///
+    /// Use `CbKind::duration`, `CbKind::handle`, `CbKind::io_mode`, `CbKind::multi_handles` and
+    /// `CbKind::deadline` to find out when the callback should be called. For timers, prefer
+    /// `deadline`, as `duration` is relative to when it is called.
+    /// Return MainLoopError::Unsupported for kinds of callbacks the backend cannot handle.
+    fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError>;
+
+    /// Removes a callback before it is called, and returns it.
+    fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>>;
+
+    /// Changes what an IO callback waits for, keeping its CbId and state.
+    ///
+    /// The default implementation returns MainLoopError::Unsupported.
+    fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        Err(MainLoopError::Unsupported)
+    }
+
+    /// Returns true if `push` handles `CbKind::Source` callbacks, i e calls their
+    /// prepare, check and dispatch functions in every iteration.
+    ///
+    /// Otherwise (the default), the MainLoop calls them around `run_one`.
+    fn supports_sources(&self) -> bool { false }
+
+    /// Returns true if `push` handles `CbKind::ChildWatch` callbacks, i e reaps the child
+    /// and calls `CbKind::post_call_child` with its exit status.
+    ///
+    /// Otherwise (the default), the MainLoop turns them into IO callbacks.
+    #[cfg(unix)]
+    fn supports_child_watch(&self) -> bool { false }
+
+    /// Waits for (if `wait` is true) and dispatches events.
+    ///
+    /// To call a callback, first call `CbKind::call_mut` (or `CbKind::call_multi` for MultiIO
+    /// callbacks). If it returns false, the callback is finished: remove it and then call
+    /// `CbKind::post_call_mut`.
+    /// Returns true if something was dispatched.
+    fn run_one(&self, wait: bool) -> bool;
+}
+
+type BoxedBackend<'a> = (Box<dyn Backend<'a> + 'a>, Box<dyn SendFnOnce>);
+
+fn boxed<'a, B: Backend<'a> + 'a>(r: Result<(B, Box<dyn SendFnOnce>), MainLoopError>) -> Result<BoxedBackend<'a>, MainLoopError> {
+    r.map(|(be, sender)| (Box::new(be) as Box<dyn Backend<'a> + 'a>, sender))
+}
+
+/// Environment variable that overrides the backend selected by `MainLoop::new`.
+pub const BACKEND_ENV_VAR: &str = "THIN_MAIN_LOOP_BACKEND";
+
+/// The backends a main loop can run on.
+///
+/// Which backends are available depends on the platform and which
+/// cargo features are enabled; several of them can be compiled into the same binary.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BackendKind {
+    /// Reference implementation, built on the Rust standard library (and poll on unix).
+    RustStd,
+    /// Linux epoll, requires the "epoll" feature.
+    Epoll,
+    /// Linux io_uring, requires the "io_uring" feature.
+    IoUring,
+    /// GLib main context, requires the "glib" feature.
+    ///
+    /// GLib waits with a timeout in whole milliseconds, so timers are late by up to a millisecond.
+    Glib,
+    /// Win32 message loop, requires the "win32" feature.
+    ///
+    /// SetTimer has a resolution of about 10 ms, so short timers run late.
+    Win32,
+}
+
+impl BackendKind {
+    /// All backends compiled into this binary, in order of preference.
+    pub fn available() -> Vec<BackendKind> {
+        let mut v = vec!();
+        if cfg!(feature = "win32") { v.push(BackendKind::Win32) };
+        if cfg!(feature = "glib") { v.push(BackendKind::Glib) };
+        if cfg!(all(feature = "io_uring", target_os = "linux")) { v.push(BackendKind::IoUring) };
+        if cfg!(all(feature = "epoll", target_os = "linux")) { v.push(BackendKind::Epoll) };
+        v.push(BackendKind::RustStd);
+        v
+    }
+
+    /// Looks up a backend by name, e g "glib" or "epoll".
+    pub fn from_name(s: &str) -> Option<BackendKind> {
+        match &*s.to_lowercase() {
+            "ruststd" | "std" => Some(BackendKind::RustStd),
+            "epoll" => Some(BackendKind::Epoll),
+            "io_uring" | "iouring" => Some(BackendKind::IoUring),
+            "glib" => Some(BackendKind::Glib),
+            "win32" => Some(BackendKind::Win32),
+            _ => None,
+        }
+    }
+
+    fn create<'a>(self) -> Result<BoxedBackend<'a>, MainLoopError> {
+        match self {
+            BackendKind::RustStd => boxed(crate::ruststd::Backend::new()),
+            #[cfg(all(feature = "epoll", target_os = "linux"))]
+            BackendKind::Epoll => boxed(crate::epoll::Backend::new()),
+            #[cfg(all(feature = "io_uring", target_os = "linux"))]
+            BackendKind::IoUring => boxed(crate::io_uring::Backend::new()),
+            #[cfg(feature = "glib")]
+            BackendKind::Glib => boxed(crate::glib::Backend::new()),
+            #[cfg(feature = "win32")]
+            BackendKind::Win32 => boxed(crate::winmsg::Backend::new()),
+            #[allow(unreachable_patterns)]
+            _ => Err(MainLoopError::Unsupported),
+        }
+    }
+
+    // Either the backend from the environment, or the first available one that works.
+    fn create_default<'a>() -> Result<BoxedBackend<'a>, MainLoopError> {
+        if let Ok(name) = std::env::var(BACKEND_ENV_VAR) {
+            let kind = BackendKind::from_name(&name).ok_or_else(|| {
+                MainLoopError::Other(format!("Unknown backend '{}' in {}", name, BACKEND_ENV_VAR).into())
+            })?;
+            return kind.create();
+        }
+        let mut err = MainLoopError::Unsupported;
+        for kind in BackendKind::available() {
+            match kind.create() {
+                Ok(x) => return Ok(x),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+}
+
 lazy_static! {
     static ref THREAD_SENDER: Mutex<HashMap<ThreadId, Box<dyn SendFnOnce>>> = Default::default();
 }
@@ -63,14 +205,65 @@ pub (crate) fn call_thread_internal(thread: ThreadId, f: Box<dyn FnOnce() + Send
     sender.send(f)
 }
 
-pub (crate) fn call_internal(cb: CbKind<'static>) -> Result<(), MainLoopError> {
+// Never reset, so that a stale id from an earlier main loop on this thread
+// cannot cancel a callback in a later one.
+fn next_id(m: &MlTls) -> CbId {
+    let x = m.next_id.get() + 1;
+    m.next_id.set(x);
+    CbId(x)
+}
+
+pub (crate) fn call_internal(mut cb: CbKind<'static>) -> Result<CbId, MainLoopError> {
     ML_TLS.with(|m| {
         if !m.exists.get() { return Err(MainLoopError::NoMainLoop) }
-        m.in_queue.borrow_mut().push(cb);
+        let id = next_id(m);
+        cb.registered(id);
+        m.in_queue.borrow_mut().push((id, cb));
+        Ok(id)
+    })
+}
+
+pub (crate) fn cancel(cbid: CbId) -> Result<(), MainLoopError> {
+    ML_TLS.with(|m| {
+        if !m.exists.get() { return Err(MainLoopError::NoMainLoop) }
+        let mut q = m.in_queue.borrow_mut();
+        if let Some(idx) = q.iter().position(|x| x.0 == cbid) {
+            // Not yet handed to the backend. Drop the callback outside the borrow,
+            // in case dropping it cancels something else.
+            let cb = q.remove(idx);
+            drop(q);
+            drop(cb);
+        } else {
+            m.cancel_queue.borrow_mut().push(cbid);
+        }
         Ok(())
     })
 }
 
+pub (crate) fn set_io_direction(cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+    ML_TLS.with(|m| {
+        if !m.exists.get() { return Err(MainLoopError::NoMainLoop) }
+        m.io_dir_queue.borrow_mut().push((cbid, dir));
+        Ok(())
+    })
+}
+
+// Returned by backends' set_io_direction for ids that are not IO callbacks.
+pub (crate) fn not_an_io_callback(cbid: CbId) -> MainLoopError {
+    MainLoopError::Other(format!("{:?} is not an active I/O callback", cbid).into())
+}
+
+/// Stores an error that has no caller to return it to, see `MainLoop::take_errors`.
+pub (crate) fn report_error(e: MainLoopError) {
+    ML_TLS.with(|m| m.errors.borrow_mut().push(e));
+}
+
+/// Stores the error and terminates the main loop, for backends that cannot wait for events.
+pub (crate) fn terminate_with_error(e: MainLoopError) {
+    report_error(e);
+    terminate();
+}
+
 pub (crate) fn terminate() {
     ML_TLS.with(|m| {
         m.terminated.set(true);
@@ -78,8 +271,11 @@ pub (crate) fn terminate() {
 }
 
 pub struct MainLoop<'a> {
-    backend: Backend<'a>,
-    next_id: Cell<CbId>,
+    backend: Box<dyn Backend<'a> + 'a>,
+    // Event sources, unless the backend handles them
+    sources: RefCell<Vec<(CbId, Box<dyn EventSource + 'a>)>>,
+    // The timer that wakes up the backend for the event sources, and when it is due
+    wakeup: Cell<Option<(CbId, Instant)>>,
     _z: PhantomData<Rc<()>>, // !Send, !Sync
 }
 
@@ -88,25 +284,140 @@ impl<'a> MainLoop<'a> {
     pub fn terminate(&self) { terminate() }
     pub fn call_asap<F: FnOnce() + 'a>(&self, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::asap(f)) }
     pub fn call_after<F: FnOnce() + 'a>(&self, d: Duration, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::after(f, d)) }
+    pub fn call_at<F: FnOnce() + 'a>(&self, i: Instant, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::at(f, i)) }
     pub fn call_interval<F: FnMut() -> bool + 'a>(&self, d: Duration, f: F)  -> Result<CbId, MainLoopError> { self.push(CbKind::interval(f, d)) }
     pub fn call_io<IO: IOAble + 'a>(&self, io: IO) -> Result<CbId, MainLoopError> { self.push(CbKind::io(io)) }
-    pub fn cancel(&self, cbid: CbId) -> bool { self.backend.cancel(cbid).is_some() }
+    pub fn call_multi_io<IO: MultiIOAble + 'a>(&self, io: IO) -> Result<CbId, MainLoopError> { self.push(CbKind::multi_io(io)) }
+    pub fn add_source<S: EventSource + 'a>(&self, s: S) -> Result<CbId, MainLoopError> { self.push(CbKind::source(s)) }
+    #[cfg(unix)]
+    pub fn call_signal<F: FnMut(crate::Signal) -> bool + 'a>(&self, signum: libc::c_int, f: F) -> Result<CbId, MainLoopError> {
+        self.call_io(crate::signal::SignalWatch::new(signum, f)?)
+    }
+    #[cfg(unix)]
+    pub fn call_child_watch<F: FnOnce(std::process::ExitStatus) + 'a>(&self, pid: libc::pid_t, f: F) -> Result<CbId, MainLoopError> {
+        crate::child::check_pid(pid)?;
+        self.push(CbKind::child_watch(f, pid))
+    }
+    #[cfg(unix)]
+    pub fn call_at_time<F: FnOnce() + 'a>(&self, t: std::time::SystemTime, f: F) -> Result<CbId, MainLoopError> {
+        let mut f = Some(f);
+        self.call_multi_io(crate::calendar::WallTimer::new(t, move |_| { if let Some(f) = f.take() { f() }; false })?)
+    }
+    #[cfg(unix)]
+    pub fn call_schedule<S, F>(&self, s: S, f: F) -> Result<CbId, MainLoopError>
+    where S: crate::calendar::Schedule + 'a, F: FnMut(std::time::SystemTime) -> bool + 'a {
+        self.call_multi_io(crate::calendar::WallTimer::new(s, f)?)
+    }
+    #[cfg(target_os = "linux")]
+    pub fn call_watch_path<P: AsRef<std::path::Path>, F: FnMut(crate::FsEvent) -> bool + 'a>(&self, path: P, mask: crate::WatchMask, f: F) -> Result<CbId, MainLoopError> {
+        self.call_io(crate::inotify::PathWatch::new(path.as_ref(), mask, f)?)
+    }
 
-    fn push(&self, cb: CbKind<'a>) -> Result<CbId, MainLoopError> {
-        let x = self.next_id.get();
-        self.next_id.set(CbId(x.0 + 1));
-        self.backend.push(x, cb)?;
+    pub fn cancel(&self, cbid: CbId) -> bool {
+        let mut sources = self.sources.borrow_mut();
+        if let Some(idx) = sources.iter().position(|x| x.0 == cbid) {
+            let s = sources.remove(idx);
+            drop(sources);
+            drop(s);
+            return true;
+        }
+        drop(sources);
+        self.backend.cancel(cbid).is_some()
+    }
+
+    /// Changes whether an IO callback waits for reading, writing, or both.
+    ///
+    /// The callback keeps its CbId, unlike when cancelling and re-adding it.
+    pub fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        self.backend.set_io_direction(cbid, dir)
+    }
+
+    /// Takes the errors that happened while the main loop was running.
+    ///
+    /// These are errors that could not be returned to a caller, e g when a callback added
+    /// with `call_asap` and friends from inside another callback could not be handed to the
+    /// backend, in which case the callback has been dropped. If the backend fails to wait for
+    /// events, the error is stored here and the main loop is terminated.
+    pub fn take_errors(&self) -> Vec<MainLoopError> {
+        ML_TLS.with(|m| mem::take(&mut *m.errors.borrow_mut()))
+    }
+
+    fn push(&self, mut cb: CbKind<'a>) -> Result<CbId, MainLoopError> {
+        let x = ML_TLS.with(next_id);
+        cb.registered(x);
+        self.push_with_id(x, cb)?;
         Ok(x)
     }
 
+    fn push_with_id(&self, id: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+        match cb {
+            CbKind::Source(s) if !self.backend.supports_sources() => {
+                self.sources.borrow_mut().push((id, s));
+                Ok(())
+            }
+            #[cfg(unix)]
+            CbKind::ChildWatch(f, pid) if !self.backend.supports_child_watch() => {
+                let mut cb = CbKind::io(crate::child::ChildWatch::new(pid, f)?);
+                cb.registered(id);
+                self.backend.push(id, cb)
+            }
+            cb => self.backend.push(id, cb),
+        }
+    }
+
+    // Runs the backend once, and calls into the event sources it does not handle itself.
+    fn run_backend(&self, wait: bool) {
+        if self.sources.borrow().is_empty() {
+            self.backend.run_one(wait);
+            return;
+        }
+        let mut sources = mem::take(&mut *self.sources.borrow_mut());
+        let timeout = sources.iter_mut().filter_map(|(_, s)| s.prepare()).min();
+        match timeout {
+            Some(t) if wait && t > Duration::from_secs(0) => {
+                // Make sure the backend wakes up in time to check the sources. A pending timer
+                // that is due no later is kept; waking up early just means preparing again.
+                let now = Instant::now();
+                let deadline = now + t;
+                let pending = self.wakeup.get().filter(|(_, d)| *d > now);
+                match pending {
+                    Some((_, d)) if d <= deadline => {},
+                    _ => {
+                        if let Some((id, _)) = pending { self.backend.cancel(id); }
+                        let id = ML_TLS.with(next_id);
+                        let timer = self.backend.push(id, CbKind::at(|| {}, deadline));
+                        self.wakeup.set(timer.ok().map(|_| (id, deadline)));
+                    }
+                }
+                self.backend.run_one(true);
+            }
+            Some(_) => { self.backend.run_one(false); },
+            None => { self.backend.run_one(wait); },
+        }
+        sources.retain_mut(|(_, s)| !s.check() || s.dispatch());
+        let mut cur = self.sources.borrow_mut();
+        sources.append(&mut cur);
+        *cur = sources;
+    }
+
     fn run_wrapper<F: FnOnce()>(&self, f: F) -> bool {
         ML_TLS.with(|m| {
             if m.terminated.get() { return false; }
-            {
-                let mut q = m.in_queue.borrow_mut();
-                for cbk in q.drain(..) {
-                    self.push(cbk).unwrap(); // TODO: Should probably be reported better
-                }
+            // Cancels first: they refer to callbacks pushed earlier, and a new callback
+            // might reuse the fd of a cancelled one.
+            let cancelled: Vec<_> = m.cancel_queue.borrow_mut().drain(..).collect();
+            for id in cancelled {
+                self.cancel(id);
+            }
+            let queued = mem::take(&mut *m.in_queue.borrow_mut());
+            for (id, cbk) in queued {
+                // The caller already got an Ok, so the error is stored instead. The callback is dropped.
+                if let Err(e) = self.push_with_id(id, cbk) { report_error(e) }
+            }
+            let dirs: Vec<_> = m.io_dir_queue.borrow_mut().drain(..).collect();
+            for (id, dir) in dirs {
+                // Fails if the callback has finished in the meantime
+                let _ = self.backend.set_io_direction(id, dir);
             }
             if m.running.get() { panic!("Reentrant call to MainLoop") }
             m.running.set(true);
@@ -122,7 +433,7 @@ impl<'a> MainLoop<'a> {
     /// Runs the main loop until terminated.
     pub fn run(&mut self) {
         while self.run_wrapper(|| {
-            self.backend.run_one(true);
+            self.run_backend(true);
         }) {}
     }
 
@@ -131,16 +442,37 @@ impl<'a> MainLoop<'a> {
     /// Returns false if the mainloop was terminated.
     pub fn run_one(&mut self, allow_wait: bool) -> bool {
         self.run_wrapper(|| {
-            self.backend.run_one(allow_wait);
+            self.run_backend(allow_wait);
         })
     }
 
     /// Creates a new main loop
+    ///
+    /// The backend is taken from the THIN_MAIN_LOOP_BACKEND environment variable
+    /// if set, otherwise the first of `BackendKind::available()` that can be initialized.
     pub fn new() -> Result<Self, MainLoopError> {
+        Self::new_internal(BackendKind::create_default)
+    }
+
+    /// Creates a new main loop, running on a specific backend.
+    ///
+    /// Returns MainLoopError::Unsupported if the backend is not compiled in.
+    pub fn with_backend(kind: BackendKind) -> Result<Self, MainLoopError> {
+        Self::new_internal(|| kind.create())
+    }
+
+    /// Creates a new main loop, running on a backend implemented outside this crate.
+    ///
+    /// The sender is used by `call_thread` to reach this main loop from other threads.
+    pub fn with_custom_backend<B: Backend<'a> + 'a>(backend: B, sender: Box<dyn SendFnOnce>) -> Result<Self, MainLoopError> {
+        Self::new_internal(|| boxed(Ok((backend, sender))))
+    }
+
+    fn new_internal<F: FnOnce() -> Result<BoxedBackend<'a>, MainLoopError>>(f: F) -> Result<Self, MainLoopError> {
         ML_TLS.with(|m| {
             if m.exists.get() { return Err(MainLoopError::TooManyMainLoops) };
 
-            let (be, sender) = Backend::new()?;
+            let (be, sender) = f()?;
             let thread_id = std::thread::current().id();
             {
                 let mut s = THREAD_SENDER.lock().unwrap();
@@ -149,6 +481,9 @@ impl<'a> MainLoop<'a> {
             }
 
             m.in_queue.borrow_mut().clear();
+            m.cancel_queue.borrow_mut().clear();
+            m.io_dir_queue.borrow_mut().clear();
+            m.errors.borrow_mut().clear();
             m.current_panic.borrow_mut().take();
             m.terminated.set(false);
             m.running.set(false);
@@ -156,7 +491,8 @@ impl<'a> MainLoop<'a> {
 
             Ok(MainLoop { 
                 backend: be,
-                next_id: Cell::new(CbId(1)),
+                sources: Default::default(),
+                wakeup: Cell::new(None),
                 _z: PhantomData 
             })
         })
@@ -180,16 +516,15 @@ fn borrowed() {
         ml.call_asap(|| { x = true; terminate(); }).unwrap();
         ml.run();
     }
-    assert_eq!(x, true);
+    assert!(x);
 }
 
 #[test]
 fn asap_static() {
     use std::rc::Rc;
 
-    let x;
+    let x = Rc::new(Cell::new(0));
     let mut ml = MainLoop::new().unwrap();
-    x = Rc::new(Cell::new(0));
     let xcl = x.clone();
     ml.call_asap(|| { 
         assert_eq!(x.get(), 0);
@@ -207,13 +542,12 @@ fn asap_static() {
 #[test]
 fn after() {
     use std::time::Instant;
-    let x;
+    let x = Cell::new(false);
     let mut ml = MainLoop::new().unwrap();
-    x = Cell::new(false);
     let n = Instant::now();
     ml.call_after(Duration::from_millis(300), || { x.set(true); terminate(); }).unwrap();
     ml.run();
-    assert_eq!(x.get(), true);
+    assert!(x.get());
     let n2 = Instant::now();
     // Windows seems to have an accuracy of 10 - 20 ms
     if (n2 - n) < Duration::from_millis(280) {
@@ -246,6 +580,59 @@ fn interval() {
     assert!(Instant::now() - n >= Duration::from_millis(400)); 
 }
 
+#[test]
+fn short_timers() {
+    use std::time::Instant;
+    for b in BackendKind::available() {
+        // GLib rounds its poll timeout up to whole milliseconds, and Win32 timers
+        // have a resolution of about 10 ms
+        if b == BackendKind::Glib || b == BackendKind::Win32 { continue; }
+        // Timers rounded to whole milliseconds would take at least 200 ms
+        fn chain(left: Rc<Cell<u32>>) {
+            crate::call_after(Duration::from_micros(100), move || {
+                left.set(left.get() - 1);
+                if left.get() == 0 { terminate() } else { chain(left) }
+            }).unwrap();
+        }
+        let left = Rc::new(Cell::new(200));
+        let mut ml = MainLoop::with_backend(b).unwrap();
+        let n = Instant::now();
+        ml.call_asap(move || chain(left)).unwrap();
+        ml.run();
+        let elapsed = Instant::now() - n;
+        assert!(elapsed >= Duration::from_millis(20), "{:?}: {:?}", b, elapsed);
+        // Leaves room for a loaded machine, while still telling apart rounded timers
+        assert!(elapsed < Duration::from_millis(150), "{:?}: {:?}", b, elapsed);
+    }
+}
+
+#[test]
+fn call_at() {
+    for b in BackendKind::available() {
+        let mut ml = MainLoop::with_backend(b).unwrap();
+        let start = Instant::now();
+        let fired = Rc::new(RefCell::new(vec!()));
+        // A chain of deadlines computed from the start, not from when each callback ran
+        fn chain(start: Instant, k: u32, fired: Rc<RefCell<Vec<(Instant, Instant)>>>) {
+            let deadline = start + Duration::from_millis(20) * k;
+            crate::call_at(deadline, move || {
+                fired.borrow_mut().push((deadline, Instant::now()));
+                if k == 5 { terminate() } else { chain(start, k + 1, fired) }
+            }).unwrap();
+        }
+        let f2 = fired.clone();
+        // Already passed, so it runs right away
+        ml.call_at(start - Duration::from_millis(10), move || chain(start, 1, f2)).unwrap();
+        ml.run();
+        let fired = fired.borrow();
+        assert_eq!(fired.len(), 5);
+        for (deadline, at) in fired.iter() {
+            assert!(at >= deadline, "{:?}", b);
+        }
+        assert!(fired[4].1 - start < Duration::from_millis(150), "{:?}", b);
+    }
+}
+
 #[test]
 fn thread_test() {
     use std::thread;
@@ -294,7 +681,7 @@ fn io_test() {
     let mut reply2 = String::new();
     let wr = IOReader { io: io, f: move |io: &mut TcpStream, x| {
         println!("{:?}", x);
-        // assert_eq!(x.unwrap(), IODirection::Read);
+        // assert!(x.readable);
         let r = io.read_to_string(&mut reply2);
         println!("r = {:?}, len = {}", r, reply2.len());
         if let Ok(n) = r {
@@ -314,6 +701,475 @@ fn io_test() {
     ml.run();
 }
 
+#[cfg(unix)]
+#[test]
+fn io_socketpair() {
+    use std::os::unix::net::UnixStream;
+    use std::io::{Write, Read};
+    use crate::IOReader;
+
+    let (mut a, b) = UnixStream::pair().unwrap();
+    b.set_nonblocking(true).unwrap();
+    let mut reply = vec!();
+    let mut ml = MainLoop::new().unwrap();
+    let wr = IOReader { io: b, f: |io: &mut UnixStream, x| {
+        assert!(x.readable);
+        let mut buf = [0u8; 16];
+        if let Ok(n) = io.read(&mut buf) {
+            reply.extend_from_slice(&buf[..n]);
+            if n == 0 { terminate(); }
+        }
+    }};
+    ml.call_io(wr).unwrap();
+    ml.call_after(Duration::from_millis(50), || {
+        a.write_all(b"Hello world").unwrap();
+        a.shutdown(std::net::Shutdown::Both).unwrap();
+    }).unwrap();
+    ml.run();
+    drop(ml);
+    assert_eq!(&reply[..], b"Hello world");
+}
+
+#[cfg(unix)]
+#[test]
+fn io_hangup() {
+    use std::os::unix::net::UnixStream;
+    use crate::{IOReader, IOEvent};
+
+    for kind in BackendKind::available() {
+        let (a, b) = UnixStream::pair().unwrap();
+        b.set_nonblocking(true).unwrap();
+        let mut events = vec!();
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            ml.call_io(IOReader { io: b, f: |_: &mut UnixStream, ev: IOEvent| {
+                events.push(ev);
+                terminate();
+            }}).unwrap();
+            ml.call_after(Duration::from_millis(20), move || drop(a)).unwrap();
+            ml.run();
+        }
+        assert_eq!(events.len(), 1, "{:?}", kind);
+        assert!(events[0].hangup, "{:?}: {:?}", kind, events[0]);
+        assert!(!events[0].error, "{:?}: {:?}", kind, events[0]);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn io_set_direction() {
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::io::AsRawFd;
+    use crate::{IOEvent, CbHandle};
+
+    struct Io<'b>(UnixStream, &'b mut Vec<IOEvent>);
+    impl IOAble for Io<'_> {
+        fn handle(&self) -> CbHandle { CbHandle(self.0.as_raw_fd()) }
+        fn direction(&self) -> IODirection { IODirection::None }
+        fn on_rw(&mut self, ev: IOEvent) -> bool {
+            self.1.push(ev);
+            terminate();
+            true
+        }
+    }
+
+    for kind in BackendKind::available() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let mut events = vec!();
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            let id = ml.call_io(Io(a, &mut events)).unwrap();
+            // Nothing to read, so this should not fire
+            ml.set_io_direction(id, IODirection::Read).unwrap();
+            ml.call_after(Duration::from_millis(20), move || {
+                crate::set_io_direction(id, IODirection::Write).unwrap();
+            }).unwrap();
+            ml.run();
+            assert!(ml.set_io_direction(CbId(1000), IODirection::Read).is_err());
+        }
+        assert_eq!(events.len(), 1, "{:?}", kind);
+        assert!(events[0].writable && !events[0].readable, "{:?}: {:?}", kind, events[0]);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn io_shared_fd() {
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::io::{Read, Write};
+    use crate::{IOEvent, CbHandle};
+
+    // A reader and a writer on the same socket, like a protocol client with an IOWriter
+    struct Reader<'b>(UnixStream, &'b RefCell<Vec<u8>>);
+    impl IOAble for Reader<'_> {
+        fn handle(&self) -> CbHandle { CbHandle(self.0.as_raw_fd()) }
+        fn direction(&self) -> IODirection { IODirection::Read }
+        fn on_rw(&mut self, ev: IOEvent) -> bool {
+            assert!(ev.readable && !ev.writable);
+            let mut buf = [0u8; 16];
+            let n = self.0.read(&mut buf).unwrap();
+            self.1.borrow_mut().extend_from_slice(&buf[..n]);
+            terminate();
+            true
+        }
+    }
+    struct Writer(RawFd, UnixStream);
+    impl IOAble for Writer {
+        fn handle(&self) -> CbHandle { CbHandle(self.0) }
+        fn direction(&self) -> IODirection { IODirection::Write }
+        fn on_rw(&mut self, ev: IOEvent) -> bool {
+            assert!(ev.writable && !ev.readable);
+            self.1.write_all(b"Ping").unwrap();
+            false
+        }
+    }
+
+    for kind in BackendKind::available() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let received = RefCell::new(vec!());
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            let fd = a.as_raw_fd();
+            let a2 = a.try_clone().unwrap();
+            ml.call_io(Reader(a, &received)).unwrap();
+            ml.call_io(Writer(fd, a2)).unwrap();
+            let b = &mut b;
+            // The writer is gone by now, the reader must still be registered
+            ml.call_after(Duration::from_millis(20), move || b.write_all(b"Pong").unwrap()).unwrap();
+            ml.run();
+            assert!(ml.take_errors().is_empty(), "{:?}", kind);
+        }
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Ping", "{:?}", kind);
+        assert_eq!(&*received.borrow(), b"Pong", "{:?}", kind);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn io_oneshot() {
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::io::AsRawFd;
+    use std::io::Write;
+    use crate::{IOEvent, IOMode, CbHandle};
+
+    struct Io<'b>(UnixStream, &'b Cell<u32>);
+    impl IOAble for Io<'_> {
+        fn handle(&self) -> CbHandle { CbHandle(self.0.as_raw_fd()) }
+        fn direction(&self) -> IODirection { IODirection::Read }
+        fn mode(&self) -> IOMode { IOMode::OneShot }
+        fn on_rw(&mut self, ev: IOEvent) -> bool {
+            // Don't read, so the handle stays readable
+            self.1.set(self.1.get() + 1);
+            if self.1.get() == 2 { terminate(); }
+            true
+        }
+    }
+
+    for kind in BackendKind::available() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        b.write_all(b"x").unwrap();
+        let calls = Cell::new(0);
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            let id = ml.call_io(Io(a, &calls)).unwrap();
+            let calls = &calls;
+            ml.call_after(Duration::from_millis(30), move || {
+                assert_eq!(calls.get(), 1, "{:?}", kind);
+                crate::set_io_direction(id, IODirection::Read).unwrap();
+            }).unwrap();
+            ml.run();
+        }
+        assert_eq!(calls.get(), 2, "{:?}", kind);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn multi_io() {
+    use std::os::unix::net::UnixStream;
+    use std::os::unix::io::AsRawFd;
+    use std::io::Write;
+    use std::time::Instant;
+    use crate::{IOEvent, CbHandle};
+
+    struct Multi<'b> {
+        a: [UnixStream; 2],
+        b2: UnixStream,
+        deadline: Option<Instant>,
+        calls: &'b mut Vec<Vec<CbHandle>>,
+    }
+    impl MultiIOAble for Multi<'_> {
+        fn handles(&self) -> Vec<(CbHandle, IODirection)> {
+            self.a.iter().map(|a| (CbHandle(a.as_raw_fd()), IODirection::Read)).collect()
+        }
+        fn deadline(&self) -> Option<Instant> { self.deadline }
+        fn on_ready(&mut self, ready: &[(CbHandle, IOEvent)]) -> bool {
+            assert!(ready.iter().all(|(_, ev)| ev.readable));
+            self.calls.push(ready.iter().map(|x| x.0).collect());
+            if ready.is_empty() {
+                // Deadline passed
+                self.deadline = None;
+                self.b2.write_all(b"x").unwrap();
+            } else { terminate(); }
+            true
+        }
+    }
+
+    for kind in BackendKind::available() {
+        let (a1, _b1) = UnixStream::pair().unwrap();
+        let (a2, b2) = UnixStream::pair().unwrap();
+        let fd2 = CbHandle(a2.as_raw_fd());
+        let mut calls = vec!();
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            let deadline = Some(Instant::now() + Duration::from_millis(20));
+            let m = Multi { a: [a1, a2], b2: b2, deadline: deadline, calls: &mut calls };
+            ml.call_multi_io(m).unwrap();
+            ml.run();
+        }
+        assert_eq!(calls, vec!(vec!(), vec!(fd2)), "{:?}", kind);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn multi_io_unwatchable_handle() {
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+    use crate::{IOEvent, CbHandle};
+
+    // Switches to /dev/null after the first call, which epoll cannot watch
+    struct Multi<'b>(Option<std::fs::File>, &'b Cell<u32>);
+    impl MultiIOAble for Multi<'_> {
+        fn handles(&self) -> Vec<(CbHandle, IODirection)> {
+            self.0.iter().map(|f| (CbHandle(f.as_raw_fd()), IODirection::Read)).collect()
+        }
+        fn deadline(&self) -> Option<Instant> { if self.0.is_none() { Some(Instant::now()) } else { None } }
+        fn on_ready(&mut self, _: &[(CbHandle, IOEvent)]) -> bool {
+            self.1.set(self.1.get() + 1);
+            if self.0.is_some() { terminate(); }
+            self.0 = Some(std::fs::File::open("/dev/null").unwrap());
+            true
+        }
+    }
+
+    for kind in BackendKind::available() {
+        let calls = Cell::new(0);
+        let mut ml = MainLoop::with_backend(kind).unwrap();
+        ml.call_multi_io(Multi(None, &calls)).unwrap();
+        ml.call_after(Duration::from_millis(20), terminate).unwrap();
+        ml.run();
+        // Either the backend can poll /dev/null, or the callback is removed and the error reported
+        let errors = ml.take_errors();
+        assert!((calls.get() >= 2 && errors.is_empty()) || (calls.get() == 1 && errors.len() == 1),
+            "{:?}: {} calls, {:?}", kind, calls.get(), errors);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn io_thread_wakeup() {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use crate::IOReader;
+
+    // An I/O source that never triggers must not stop call_thread from waking us up
+    let (_a, b) = UnixStream::pair().unwrap();
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_io(IOReader { io: b, f: |_: &mut UnixStream, _| { panic!("Nothing to read") }}).unwrap();
+    let id = thread::current().id();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        crate::call_thread(id, terminate).unwrap();
+    });
+    ml.run();
+}
+
+#[test]
+fn all_backends() {
+    use std::thread;
+
+    for kind in BackendKind::available() {
+        let mut x = 0;
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            let id = thread::current().id();
+            ml.call_asap(|| { x += 1; }).unwrap();
+            ml.call_after(Duration::from_millis(20), move || {
+                thread::spawn(move || { crate::call_thread(id, terminate).unwrap(); });
+            }).unwrap();
+            ml.run();
+        }
+        assert_eq!(x, 1, "{:?}", kind);
+    }
+    assert!(MainLoop::with_backend(BackendKind::RustStd).is_ok());
+    if cfg!(not(feature = "win32")) {
+        assert!(matches!(MainLoop::with_backend(BackendKind::Win32), Err(MainLoopError::Unsupported)));
+    }
+    assert_eq!(BackendKind::from_name("GLib"), Some(BackendKind::Glib));
+}
+
+#[test]
+fn event_source() {
+    use std::time::Instant;
+    use std::collections::VecDeque;
+
+    // Polls a "register" every 5 ms, and a message queue
+    struct Polled<'b> { ready_at: Instant, polls: &'b Cell<u32> }
+    impl EventSource for Polled<'_> {
+        fn prepare(&mut self) -> Option<Duration> { Some(Duration::from_millis(5)) }
+        fn check(&mut self) -> bool { self.polls.set(self.polls.get() + 1); Instant::now() >= self.ready_at }
+        fn dispatch(&mut self) -> bool {
+            crate::call_asap(terminate).unwrap();
+            false
+        }
+    }
+    struct Queue<'b>(&'b RefCell<VecDeque<u32>>, &'b RefCell<Vec<u32>>);
+    impl EventSource for Queue<'_> {
+        fn prepare(&mut self) -> Option<Duration> {
+            if self.0.borrow().is_empty() { None } else { Some(Duration::from_secs(0)) }
+        }
+        fn check(&mut self) -> bool { !self.0.borrow().is_empty() }
+        fn dispatch(&mut self) -> bool {
+            let x = self.0.borrow_mut().pop_front().unwrap();
+            self.1.borrow_mut().push(x);
+            true
+        }
+    }
+
+    for kind in BackendKind::available() {
+        let polls = Cell::new(0);
+        let queue = RefCell::new(VecDeque::new());
+        let received = RefCell::new(vec!());
+        {
+            let mut ml = MainLoop::with_backend(kind).unwrap();
+            ml.add_source(Polled { ready_at: Instant::now() + Duration::from_millis(50), polls: &polls }).unwrap();
+            let q = ml.add_source(Queue(&queue, &received)).unwrap();
+            ml.call_after(Duration::from_millis(10), || {
+                queue.borrow_mut().extend(&[1, 2, 3]);
+            }).unwrap();
+            ml.run();
+            assert!(ml.cancel(q));
+        }
+        assert_eq!(&*received.borrow(), &[1, 2, 3], "{:?}", kind);
+        // The main loop must have woken up for polling, although nothing else happened
+        assert!(polls.get() >= 5, "{:?}: {}", kind, polls.get());
+    }
+}
+
+#[test]
+fn source_wakeup_timer() {
+    // Counts the timers handed to the backend
+    struct Counting<'a>(crate::ruststd::Backend<'a>, &'a Cell<u32>);
+    impl<'a> Backend<'a> for Counting<'a> {
+        fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+            if cb.deadline().is_some() { self.1.set(self.1.get() + 1) }
+            self.0.push(cbid, cb)
+        }
+        fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> { self.0.cancel(cbid) }
+        fn run_one(&self, wait: bool) -> bool { self.0.run_one(wait) }
+    }
+    struct Idle;
+    impl EventSource for Idle {
+        fn prepare(&mut self) -> Option<Duration> { Some(Duration::from_millis(100)) }
+        fn check(&mut self) -> bool { false }
+        fn dispatch(&mut self) -> bool { true }
+    }
+
+    let timers = Cell::new(0);
+    {
+        let (be, sender) = crate::ruststd::Backend::new().unwrap();
+        let mut ml = MainLoop::with_custom_backend(Counting(be, &timers), sender).unwrap();
+        ml.add_source(Idle).unwrap();
+        let mut left = 20;
+        ml.call_interval(Duration::from_millis(1), move || {
+            left -= 1;
+            if left == 0 { terminate() }
+            left > 0
+        }).unwrap();
+        ml.run();
+    }
+    // The interval, and a single wakeup timer for the source
+    assert_eq!(timers.get(), 2);
+}
+
+#[test]
+fn custom_backend() {
+    use std::collections::VecDeque;
+
+    // A backend that only supports call_asap and call_thread
+    struct AsapOnly<'a>(RefCell<VecDeque<(CbId, CbKind<'a>)>>);
+    impl<'a> Backend<'a> for AsapOnly<'a> {
+        fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+            if cb.duration().is_some() || cb.handle().is_some() { return Err(MainLoopError::Unsupported) }
+            self.0.borrow_mut().push_back((cbid, cb));
+            Ok(())
+        }
+        fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+            let mut q = self.0.borrow_mut();
+            let idx = q.iter().position(|x| x.0 == cbid)?;
+            q.remove(idx).map(|x| x.1)
+        }
+        fn run_one(&self, _: bool) -> bool {
+            let cb = self.0.borrow_mut().pop_front();
+            cb.map(|(_, mut kind)| { assert!(!kind.call_mut(None)); kind.post_call_mut(); }).is_some()
+        }
+    }
+    struct NoThreads;
+    impl SendFnOnce for NoThreads {
+        fn send(&self, _: Box<dyn FnOnce() + Send + 'static>) -> Result<(), MainLoopError> { Err(MainLoopError::Unsupported) }
+    }
+
+    let mut x = 0;
+    {
+        let mut ml = MainLoop::with_custom_backend(AsapOnly(Default::default()), Box::new(NoThreads)).unwrap();
+        ml.call_asap(|| {
+            x += 1;
+            // Accepted here, but rejected by the backend later
+            crate::call_after(Duration::from_millis(1), || {}).unwrap();
+            crate::call_asap(terminate).unwrap();
+        }).unwrap();
+        assert!(matches!(ml.call_after(Duration::from_millis(1), || {}), Err(MainLoopError::Unsupported)));
+        ml.run();
+        let errors = ml.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], MainLoopError::Unsupported));
+        assert!(ml.take_errors().is_empty());
+    }
+    assert_eq!(x, 1);
+}
+
+#[test]
+fn cancel_static() {
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_asap(|| {
+        let id = crate::call_after(Duration::from_millis(10), || { panic!("This should have been cancelled!"); }).unwrap();
+        crate::call_asap(move || { crate::cancel(id).unwrap(); }).unwrap();
+        let id = crate::call_asap(|| { panic!("This should have been cancelled!"); }).unwrap();
+        crate::cancel(id).unwrap();
+    }).unwrap();
+    ml.call_after(Duration::from_millis(50), terminate).unwrap();
+    ml.run();
+}
+
+#[test]
+fn stale_id_from_earlier_loop() {
+    let stale = {
+        let ml = MainLoop::new().unwrap();
+        ml.call_asap(|| {}).unwrap()
+    };
+    let mut ml = MainLoop::new().unwrap();
+    let called = Rc::new(Cell::new(false));
+    let c = called.clone();
+    ml.call_asap(move || { c.set(true); terminate(); }).unwrap();
+    assert!(!ml.cancel(stale));
+    ml.run();
+    assert!(called.get());
+}
+
 #[test]
 fn panic_inside_cb() {
     let mut ml = MainLoop::new().unwrap();
@@ -329,8 +1185,8 @@ fn cancel_cb() {
     let mut ml = MainLoop::new().unwrap();
     let id = ml.call_asap(|| { panic!("This should have been cancelled!"); }).unwrap();
     ml.call_after(Duration::from_millis(50), terminate).unwrap();
-    assert_eq!(ml.cancel(id), true);
-    assert_eq!(ml.cancel(id), false);
+    assert!(ml.cancel(id));
+    assert!(!ml.cancel(id));
     ml.run();
 }
 
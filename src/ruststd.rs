@@ -2,78 +2,227 @@ use std::cell::RefCell;
 use std::collections::VecDeque;
 use crate::{CbKind, CbId, MainLoopError};
 use std::time::{Instant, Duration};
+#[cfg(not(unix))]
 use std::thread;
 use crate::mainloop::SendFnOnce;
 use std::sync::mpsc::{channel, Sender, Receiver};
 
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use crate::{CbHandle, IOEvent, IODirection, IOMode, dir_to_poll};
+
 struct Data<'a> {
     id: CbId,
     next: Instant,
     kind: CbKind<'a>,
 }
 
+// ppoll takes a timespec, so timers are not rounded to whole milliseconds
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn sys_poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> libc::c_int {
+    let ts = timeout.map(|t| libc::timespec {
+        tv_sec: std::cmp::min(t.as_secs(), libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: t.subsec_nanos() as libc::c_long,
+    });
+    let tsp = ts.as_ref().map(|ts| ts as *const _).unwrap_or(std::ptr::null());
+    unsafe { libc::ppoll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, tsp, std::ptr::null()) }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android", target_os = "freebsd"))))]
+fn sys_poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> libc::c_int {
+    let ms = timeout.map(|t| {
+        // Round upwards, so we don't wake up just before the timer is due
+        let ms = t.as_micros().div_ceil(1000);
+        if ms > libc::c_int::MAX as u128 { libc::c_int::MAX } else { ms as libc::c_int }
+    }).unwrap_or(-1);
+    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, ms) }
+}
+
+#[cfg(unix)]
+struct IoData<'a> {
+    id: CbId,
+    fd: RawFd,
+    events: libc::c_short,
+    // False for one-shot callbacks, between a call and set_io_direction
+    armed: bool,
+    kind: CbKind<'a>,
+}
+
+#[cfg(unix)]
+struct MultiData<'a> {
+    id: CbId,
+    kind: CbKind<'a>,
+}
+
 struct TSender {
+    #[cfg(not(unix))]
     thread: thread::Thread,
+    // Self-pipe: writing a byte here wakes up the poll call
+    #[cfg(unix)]
+    wake: UnixStream,
     sender: Sender<Box<dyn FnOnce() + Send + 'static>>,
 }
 
 impl SendFnOnce for TSender {
     fn send(&self, f: Box<dyn FnOnce() + Send + 'static>) -> Result<(), MainLoopError> {
         self.sender.send(f).map_err(|e| MainLoopError::Other(e.into()))?;
+        #[cfg(not(unix))]
         self.thread.unpark();
+        #[cfg(unix)]
+        match (&self.wake).write(&[1]) {
+            // If the pipe is full, the main loop is going to wake up anyway
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => return Err(MainLoopError::Other(e.into())),
+            Ok(_) => {},
+        }
         Ok(())
     }
 }
 
 pub struct Backend<'a> {
     data: RefCell<VecDeque<Data<'a>>>,
+    #[cfg(unix)]
+    io: RefCell<Vec<IoData<'a>>>,
+    #[cfg(unix)]
+    multi: RefCell<Vec<MultiData<'a>>>,
+    #[cfg(unix)]
+    wake: UnixStream,
     recv: Receiver<Box<dyn FnOnce() + Send + 'static>>,
 }
 
 impl<'a> Backend<'a> {
     pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
         let (tx, rx) = channel();
-        let be = Backend { recv: rx, data: Default::default() };
-        let sender = TSender { thread: thread::current(), sender: tx };
+        #[cfg(unix)]
+        let (wake_rx, wake_tx) = {
+            let (a, b) = UnixStream::pair().map_err(|e| MainLoopError::Other(e.into()))?;
+            a.set_nonblocking(true).map_err(|e| MainLoopError::Other(e.into()))?;
+            b.set_nonblocking(true).map_err(|e| MainLoopError::Other(e.into()))?;
+            (a, b)
+        };
+        let be = Backend {
+            recv: rx,
+            data: Default::default(),
+            #[cfg(unix)]
+            io: Default::default(),
+            #[cfg(unix)]
+            multi: Default::default(),
+            #[cfg(unix)]
+            wake: wake_rx,
+        };
+        let sender = TSender {
+            #[cfg(not(unix))]
+            thread: thread::current(),
+            #[cfg(unix)]
+            wake: wake_tx,
+            sender: tx
+        };
         Ok((be, Box::new(sender)))
     }
 
-    pub fn run_one(&self, wait: bool) -> bool {
-        let mut d = self.data.borrow_mut();
-        let mut item = d.pop_front();
-        let mut next: Option<Instant> = item.as_ref().map(|i| i.next);
+
+    #[cfg(not(unix))]
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        match timeout {
+            Some(t) if t == Duration::from_secs(0) => {},
+            Some(t) => thread::park_timeout(t),
+            None => thread::park(),
+        }
+        false
+    }
+
+    // Waits for I/O, a thread wakeup or the timeout, whichever comes first.
+    // Returns true if an I/O callback was called.
+    #[cfg(unix)]
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let mut fds = vec!(libc::pollfd { fd: self.wake.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+        let mut ids = vec!();
+        for io in self.io.borrow().iter().filter(|io| io.armed) {
+            fds.push(libc::pollfd { fd: io.fd, events: io.events, revents: 0 });
+            ids.push(io.id);
+        }
+        // MultiIO handles go after the IO handles, and might shorten the timeout
+        let mut timeout = timeout;
+        let mut multis = vec!();
         let now = Instant::now();
-        if let Some(n) = next {
-            if n > now {
-                d.push_front(item.take().unwrap());
-            } else {
-                next = d.get(0).map(|i| i.next);
+        for m in self.multi.borrow().iter() {
+            let start = fds.len();
+            for (h, dir) in m.kind.multi_handles().unwrap_or_default() {
+                fds.push(libc::pollfd { fd: h.0, events: dir_to_poll(dir), revents: 0 });
             }
+            let deadline = m.kind.deadline();
+            if let Some(d) = deadline {
+                let t = d.saturating_duration_since(now);
+                timeout = Some(timeout.map_or(t, |x| x.min(t)));
+            }
+            multis.push((m.id, deadline, start..fds.len()));
+        }
+        let r = sys_poll(&mut fds, timeout);
+        if r < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                crate::mainloop::terminate_with_error(MainLoopError::Other(e.into()));
+            }
+            return false;
         }
-        drop(d);
 
-        if item.is_none() {
-            if let Ok(cb) = self.recv.try_recv() {
-                cb();
-                return true;
+        if fds[0].revents != 0 {
+            let mut buf = [0u8; 64];
+            while let Ok(n) = (&self.wake).read(&mut buf) {
+                if n < buf.len() { break; }
             }
         }
 
-        if let Some(mut item) = item {
-            if item.kind.call_mut(None) {
-                // Remain on the main loop
-                item.next += item.kind.duration().unwrap();
-                self.push_internal(item);
-            } else { item.kind.post_call_mut() }
-            true
-        } else if wait {
-            if let Some(next) = next {
-                thread::park_timeout(next - now);
-            } else {
-                thread::park();
+        let mut called = false;
+        for (pfd, id) in fds[1..].iter().zip(ids) {
+            if pfd.revents == 0 { continue; }
+            called |= self.dispatch_io(id, IOEvent::from_poll(pfd.revents));
+        }
+        let now = Instant::now();
+        for (id, deadline, range) in multis {
+            let ready: Vec<_> = fds[range].iter().filter(|pfd| pfd.revents != 0)
+                .map(|pfd| (CbHandle(pfd.fd), IOEvent::from_poll(pfd.revents))).collect();
+            if ready.is_empty() && deadline.map(|d| d > now).unwrap_or(true) { continue; }
+            called |= self.dispatch_multi(id, &ready);
+        }
+        called
+    }
+
+    #[cfg(unix)]
+    fn dispatch_io(&self, id: CbId, ev: IOEvent) -> bool {
+        let mut item = {
+            let mut io = self.io.borrow_mut();
+            match io.iter().position(|x| x.id == id) {
+                Some(idx) => io.remove(idx),
+                None => return false,
+            }
+        };
+        if item.kind.call_mut(Some(ev)) {
+            // Edge triggering is emulated by level triggering
+            item.armed = item.kind.io_mode() != Some(IOMode::OneShot);
+            self.io.borrow_mut().push(item);
+        } else { item.kind.post_call_mut() }
+        true
+    }
+
+    #[cfg(unix)]
+    fn dispatch_multi(&self, id: CbId, ready: &[(CbHandle, IOEvent)]) -> bool {
+        let mut item = {
+            let mut multi = self.multi.borrow_mut();
+            match multi.iter().position(|x| x.id == id) {
+                Some(idx) => multi.remove(idx),
+                None => return false,
             }
-            false
-        } else { false }
+        };
+        if item.kind.call_multi(ready) {
+            self.multi.borrow_mut().push(item);
+        } else { item.kind.post_call_mut() }
+        true
     }
 
     fn push_internal(&self, item: Data<'a>) {
@@ -84,22 +233,103 @@ impl<'a> Backend<'a> {
         }
         d.insert(i, item);
     }
+}
+
+impl<'a> crate::mainloop::Backend<'a> for Backend<'a> {
+    fn push(&self, id: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+        if let Some((handle, direction)) = cb.handle() {
+            #[cfg(unix)]
+            {
+                self.io.borrow_mut().push(IoData {
+                    id: id,
+                    fd: handle.0,
+                    events: dir_to_poll(direction),
+                    armed: true,
+                    kind: cb,
+                });
+                return Ok(());
+            }
+            #[cfg(not(unix))]
+            return Err(MainLoopError::Unsupported);
+        }
 
-    pub (crate) fn push(&self, id: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
-        if cb.handle().is_some() { return Err(MainLoopError::Unsupported) };
+        if cb.multi_handles().is_some() {
+            #[cfg(unix)]
+            {
+                self.multi.borrow_mut().push(MultiData { id: id, kind: cb });
+                return Ok(());
+            }
+            #[cfg(not(unix))]
+            return Err(MainLoopError::Unsupported);
+        }
 
         self.push_internal(Data {
             id: id,
-            next: Instant::now() + cb.duration().unwrap_or(Duration::from_secs(0)),
+            next: cb.deadline().unwrap_or_else(Instant::now),
             kind: cb
         });
         Ok(())
     }
 
-    pub (crate) fn cancel(&self, id: CbId) -> Option<CbKind<'a>> {
+    fn cancel(&self, id: CbId) -> Option<CbKind<'a>> {
+        #[cfg(unix)]
+        {
+            let mut io = self.io.borrow_mut();
+            if let Some(idx) = io.iter().position(|x| x.id == id) {
+                return Some(io.remove(idx).kind);
+            }
+            let mut multi = self.multi.borrow_mut();
+            if let Some(idx) = multi.iter().position(|x| x.id == id) {
+                return Some(multi.remove(idx).kind);
+            }
+        }
         let mut d = self.data.borrow_mut();
         d.iter().position(|x| x.id == id)
             .and_then(|idx| d.remove(idx))
             .map(|data| data.kind)
     }
+
+    #[cfg(unix)]
+    fn set_io_direction(&self, id: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        let mut io = self.io.borrow_mut();
+        let item = io.iter_mut().find(|x| x.id == id).ok_or_else(|| crate::mainloop::not_an_io_callback(id))?;
+        item.events = dir_to_poll(dir);
+        item.armed = true;
+        Ok(())
+    }
+
+    fn run_one(&self, wait: bool) -> bool {
+        let mut d = self.data.borrow_mut();
+        let mut item = d.pop_front();
+        let mut next: Option<Instant> = item.as_ref().map(|i| i.next);
+        let now = Instant::now();
+        if let Some(n) = next {
+            if n > now {
+                d.push_front(item.take().unwrap());
+            } else {
+                next = d.front().map(|i| i.next);
+            }
+        }
+        drop(d);
+
+        if item.is_none() {
+            if let Ok(cb) = self.recv.try_recv() {
+                cb();
+                return true;
+            }
+        }
+
+        if let Some(mut item) = item {
+            if item.kind.call_mut(None) {
+                // Remain on the main loop
+                item.next = item.kind.deadline().unwrap();
+                self.push_internal(item);
+            } else { item.kind.post_call_mut() }
+            true
+        } else if wait {
+            self.wait(next.map(|n| n - now))
+        } else {
+            self.wait(Some(Duration::from_secs(0)))
+        }
+    }
 }
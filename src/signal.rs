@@ -0,0 +1,258 @@
+//! Unix signals, delivered as main loop callbacks.
+//!
+//! The signal handler only writes a byte to a pipe, which is async-signal-safe.
+//! The other end of the pipe is watched by the main loop like any other I/O object.
+//! A handler that was installed before ours, e g by GLib, is still called.
+//! This works the same on all backends, including glib, where g_unix_signal_source_new
+//! would only have supported a fixed set of signals.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use crate::{CbHandle, IOAble, IODirection, IOEvent, MainLoopError};
+
+/// A signal that has been received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Signal {
+    /// The signal number, e g libc::SIGINT.
+    pub signum: libc::c_int,
+    /// How many times the signal was received since the last call. Signals arriving in quick
+    /// succession might be coalesced, so this is a lower bound.
+    pub count: u32,
+}
+
+const MAX_SLOTS: usize = 64;
+
+// Registrations, read by the signal handler. A slot is free when its signum is zero.
+// Signum is claimed first and released last, so that the handler only sees complete slots.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicI32 = AtomicI32::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FD: AtomicI32 = AtomicI32::new(-1);
+static SLOT_SIGNUM: [AtomicI32; MAX_SLOTS] = [EMPTY; MAX_SLOTS];
+static SLOT_FD: [AtomicI32; MAX_SLOTS] = [NO_FD; MAX_SLOTS];
+
+// Signal numbers are below this
+const NSIG: usize = 65;
+
+// The handler that was installed before ours and its flags, per signal, so that we can chain to it.
+#[allow(clippy::declare_interior_mutable_const)]
+const DEFAULT_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+static OLD_HANDLER: [AtomicUsize; NSIG] = [DEFAULT_HANDLER; NSIG];
+static OLD_FLAGS: [AtomicI32; NSIG] = [EMPTY; NSIG];
+
+lazy_static! {
+    // Number of registrations and the previous handler, per signal.
+    static ref INSTALLED: Mutex<HashMap<libc::c_int, (usize, libc::sigaction)>> = Default::default();
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn errno_location() -> *mut libc::c_int { libc::__errno_location() }
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn errno_location() -> *mut libc::c_int { libc::__error() }
+
+type SigactionFn = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+extern "C" fn handler(signum: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    unsafe {
+        let errno = *errno_location();
+        for i in 0..MAX_SLOTS {
+            if SLOT_SIGNUM[i].load(Ordering::SeqCst) != signum { continue; }
+            let fd = SLOT_FD[i].load(Ordering::SeqCst);
+            // If the pipe is full, the byte is dropped and the signal is coalesced
+            if fd >= 0 { libc::write(fd, &1u8 as *const _ as *const libc::c_void, 1); }
+        }
+        // The default action would e g terminate the process for SIGINT, so only real handlers are called
+        let old = OLD_HANDLER[signum as usize].load(Ordering::SeqCst);
+        if old != libc::SIG_DFL && old != libc::SIG_IGN {
+            if OLD_FLAGS[signum as usize].load(Ordering::SeqCst) & libc::SA_SIGINFO != 0 {
+                mem::transmute::<libc::sighandler_t, SigactionFn>(old)(signum, info, ctx);
+            } else {
+                mem::transmute::<libc::sighandler_t, extern "C" fn(libc::c_int)>(old)(signum);
+            }
+        }
+        *errno_location() = errno;
+    }
+}
+
+fn install(signum: libc::c_int) -> io::Result<()> {
+    let mut installed = INSTALLED.lock().unwrap();
+    if let Some(x) = installed.get_mut(&signum) {
+        x.0 += 1;
+        return Ok(());
+    }
+    let old = unsafe {
+        let mut old: libc::sigaction = mem::zeroed();
+        if libc::sigaction(signum, std::ptr::null(), &mut old) < 0 { return Err(io::Error::last_os_error()) }
+        // Stored before our handler is installed, so no signal is missed by the old handler
+        OLD_HANDLER[signum as usize].store(old.sa_sigaction, Ordering::SeqCst);
+        OLD_FLAGS[signum as usize].store(old.sa_flags, Ordering::SeqCst);
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = handler as SigactionFn as libc::sighandler_t;
+        sa.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+        libc::sigemptyset(&mut sa.sa_mask);
+        if libc::sigaction(signum, &sa, std::ptr::null_mut()) < 0 {
+            OLD_HANDLER[signum as usize].store(libc::SIG_DFL, Ordering::SeqCst);
+            return Err(io::Error::last_os_error());
+        }
+        old
+    };
+    installed.insert(signum, (1, old));
+    Ok(())
+}
+
+fn uninstall(signum: libc::c_int) {
+    let mut installed = INSTALLED.lock().unwrap();
+    let last = match installed.get_mut(&signum) {
+        Some(x) => { x.0 -= 1; x.0 == 0 },
+        None => return,
+    };
+    if last {
+        let (_, old) = installed.remove(&signum).unwrap();
+        unsafe { libc::sigaction(signum, &old, std::ptr::null_mut()); }
+        OLD_HANDLER[signum as usize].store(libc::SIG_DFL, Ordering::SeqCst);
+    }
+}
+
+/// A signal handler registration, and the write end of its pipe.
+/// The handler is removed when this is dropped.
+pub (crate) struct Registration {
+    signum: libc::c_int,
+    slot: usize,
+    tx: UnixStream,
+}
+
+impl Registration {
+    /// Returns the read end of the pipe as well.
+    pub (crate) fn new(signum: libc::c_int) -> io::Result<(Self, UnixStream)> {
+        if signum <= 0 || signum as usize >= NSIG {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid signal number"));
+        }
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        let slot = (0..MAX_SLOTS).find(|&i| {
+            SLOT_SIGNUM[i].compare_exchange(0, signum, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        }).ok_or_else(|| io::Error::other("Too many signal registrations"))?;
+        SLOT_FD[slot].store(tx.as_raw_fd(), Ordering::SeqCst);
+        let r = Registration { signum: signum, slot: slot, tx: tx };
+        install(signum)?;
+        Ok((r, rx))
+    }
+
+    pub (crate) fn signum(&self) -> libc::c_int { self.signum }
+
+    /// Makes the read end readable, as if the signal had been received.
+    pub (crate) fn notify(&self) {
+        let _ = (&self.tx).write(&[1]);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        SLOT_FD[self.slot].store(-1, Ordering::SeqCst);
+        SLOT_SIGNUM[self.slot].store(0, Ordering::SeqCst);
+        uninstall(self.signum);
+    }
+}
+
+/// Reads everything from the pipe, returns the number of bytes read.
+pub (crate) fn drain(mut rx: &UnixStream) -> io::Result<u32> {
+    let mut buf = [0u8; 64];
+    let mut count = 0;
+    loop {
+        match rx.read(&mut buf) {
+            Ok(0) => return Ok(count),
+            Ok(n) => count += n as u32,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(count),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub (crate) struct SignalWatch<F: FnMut(Signal) -> bool> {
+    reg: Registration,
+    rx: UnixStream,
+    f: F,
+}
+
+impl<F: FnMut(Signal) -> bool> SignalWatch<F> {
+    pub (crate) fn new(signum: libc::c_int, f: F) -> Result<Self, MainLoopError> {
+        let (reg, rx) = Registration::new(signum).map_err(|e| MainLoopError::Other(e.into()))?;
+        Ok(SignalWatch { reg: reg, rx: rx, f: f })
+    }
+}
+
+impl<F: FnMut(Signal) -> bool> IOAble for SignalWatch<F> {
+    fn handle(&self) -> CbHandle { CbHandle(self.rx.as_raw_fd()) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: IOEvent) -> bool {
+        match drain(&self.rx) {
+            Ok(0) => true,
+            Ok(n) => (self.f)(Signal { signum: self.reg.signum, count: n }),
+            Err(_) => false,
+        }
+    }
+}
+
+#[test]
+fn signal_callback() {
+    use std::cell::Cell;
+    use crate::MainLoop;
+
+    for b in crate::BackendKind::available() {
+        let got = Cell::new(0);
+        let mut ml = MainLoop::with_backend(b).unwrap();
+        ml.call_signal(libc::SIGUSR2, |s| {
+            assert_eq!(s.signum, libc::SIGUSR2);
+            got.set(got.get() + s.count);
+            crate::terminate();
+            false
+        }).unwrap();
+        ml.call_asap(|| unsafe { libc::raise(libc::SIGUSR2); }).unwrap();
+        ml.run();
+        assert!(got.get() >= 1);
+    }
+    // The handler should be restored now
+    assert!(INSTALLED.lock().unwrap().get(&libc::SIGUSR2).is_none());
+}
+
+#[test]
+fn chain_previous_handler() {
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicU32;
+    use crate::MainLoop;
+
+    static OLD_CALLS: AtomicU32 = AtomicU32::new(0);
+    extern "C" fn old_handler(_: libc::c_int) { OLD_CALLS.fetch_add(1, Ordering::SeqCst); }
+
+    let signum = libc::SIGWINCH;
+    unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = old_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut sa.sa_mask);
+        assert_eq!(libc::sigaction(signum, &sa, std::ptr::null_mut()), 0);
+    }
+    let got = Cell::new(0);
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_signal(signum, |s| {
+            got.set(s.count);
+            crate::terminate();
+            false
+        }).unwrap();
+        ml.call_asap(|| unsafe { libc::raise(signum); }).unwrap();
+        ml.run();
+    }
+    assert!(got.get() >= 1);
+    assert_eq!(OLD_CALLS.load(Ordering::SeqCst), 1);
+    // Our handler is removed, and the old one is back
+    unsafe { libc::raise(signum); }
+    assert_eq!(OLD_CALLS.load(Ordering::SeqCst), 2);
+    unsafe { libc::signal(signum, libc::SIG_DFL); }
+}
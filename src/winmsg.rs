@@ -1,9 +1,9 @@
-use crate::{CbKind, CbId, MainLoopError, IODirection};
+use crate::{CbKind, CbId, MainLoopError, IODirection, IOEvent, IOMode};
 use crate::mainloop::{SendFnOnce, ffi_cb_wrapper};
 use winapi;
 use std::{mem, ptr};
 use std::sync::{Once, Arc};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cell::RefCell;
 
 use winapi::shared::windef::HWND;
@@ -11,6 +11,7 @@ use winapi::um::winuser;
 use winapi::um::libloaderapi;
 use winapi::um::winnt;
 use winapi::um::winsock2;
+use winapi::ctypes::c_long;
 
 struct OwnedHwnd(HWND);
 
@@ -25,13 +26,15 @@ struct BeInternal<'a> {
     wnd: Arc<OwnedHwnd>,
     cb_map: RefCell<HashMap<CbId, CbKind<'a>>>,
     socket_map: RefCell<HashMap<usize, CbId>>,
+    // Interval timers that are armed for their first expiry, rather than for their period
+    first_expiry: RefCell<HashSet<CbId>>,
 }
 
 impl<'a> BeInternal<'a> {
-    fn call_data(&self, cbid: CbId, dir: Option<Result<IODirection, std::io::Error>>) -> bool {
+    fn call_data(&self, cbid: CbId, ev: Option<IOEvent>) -> bool {
         let kind = self.cb_map.borrow_mut().remove(&cbid);
         if let Some(mut kind) = kind {
-            if kind.call_mut(dir) {
+            if kind.call_mut(ev) {
                 self.cb_map.borrow_mut().insert(cbid, kind);
                 return true;
             }
@@ -49,10 +52,20 @@ impl<'a> BeInternal<'a> {
         }
         if let Some(_) = kind.duration() {
             unsafe { winuser::KillTimer(self.wnd.0, cbid.0 as usize); }
+            self.first_expiry.borrow_mut().remove(&cbid);
         }
     }
 }
 
+fn dir_to_events(d: IODirection) -> c_long {
+    match d {
+        IODirection::None => 0,
+        IODirection::Read => winsock2::FD_READ | winsock2::FD_OOB,
+        IODirection::Write => winsock2::FD_WRITE,
+        IODirection::Both => winsock2::FD_READ | winsock2::FD_OOB | winsock2::FD_WRITE,
+    } + winsock2::FD_CLOSE
+}
+
 // Boxed because we need the pointer not to move in callbacks
 pub struct Backend<'a>(Box<BeInternal<'a>>);
 
@@ -101,19 +114,33 @@ unsafe extern "system" fn wnd_callback(wnd: HWND, msg: u32, wparam: usize, lpara
         match msg {
             WM_SOCKET => {
                 // println!("WM_Socket: {} {}", wparam, lparam);
-                let dir = match (lparam as i32) & (winsock2::FD_READ | winsock2::FD_WRITE) {
-                    0 => Ok(IODirection::None),
-                    winsock2::FD_READ => Ok(IODirection::Read),
-                    winsock2::FD_WRITE => Ok(IODirection::Write),
-                    _ => Ok(IODirection::Both),
+                // The low word is the event, the high word is the error code (WSAGETSELECTEVENT / WSAGETSELECTERROR)
+                let events = (lparam as i32) & 0xffff;
+                let ev = IOEvent {
+                    readable: events & winsock2::FD_READ != 0,
+                    writable: events & winsock2::FD_WRITE != 0,
+                    hangup: events & winsock2::FD_CLOSE != 0,
+                    error: ((lparam as u32) >> 16) != 0,
+                    priority: events & winsock2::FD_OOB != 0,
                 };
                 let cbid = *be.socket_map.borrow().get(&wparam).unwrap();
-                if !be.call_data(cbid, Some(dir)) {
+                if !be.call_data(cbid, Some(ev)) {
                     winsock2::WSAAsyncSelect(wparam, wnd, WM_SOCKET, 0);
                     be.socket_map.borrow_mut().remove(&wparam);
+                } else if be.cb_map.borrow().get(&cbid).and_then(|k| k.io_mode()) == Some(IOMode::OneShot) {
+                    // Until set_io_direction re-arms it
+                    winsock2::WSAAsyncSelect(wparam, wnd, WM_SOCKET, 0);
                 }
             },
-            winuser::WM_TIMER | WM_CALL_ASAP => {
+            winuser::WM_TIMER => {
+                let cbid = CbId(wparam as u64);
+                if be.call_data(cbid, None) && be.first_expiry.borrow_mut().remove(&cbid) {
+                    // From now on, repeat at the period
+                    let period = be.cb_map.borrow().get(&cbid).and_then(|k| k.period_millis().ok().flatten());
+                    if let Some(p) = period { winuser::SetTimer(wnd, wparam, p, None); }
+                }
+            },
+            WM_CALL_ASAP => {
                 let cbid = CbId(wparam as u64);
                 be.call_data(cbid, None);
             },
@@ -159,7 +186,8 @@ impl<'a> Backend<'a> {
         let be = Box::new(BeInternal {
             wnd: ownd.clone(),
             cb_map: Default::default(),
-            socket_map: Default::default()
+            socket_map: Default::default(),
+            first_expiry: Default::default(),
         });
         unsafe {
             let be_ptr: &BeInternal = &be;
@@ -168,44 +196,25 @@ impl<'a> Backend<'a> {
         };
         Ok((Backend(be), Box::new(ownd)))
     }
+}
 
-    pub fn run_one(&self, wait: bool) -> bool {
-        unsafe {
-            let mut msg = mem::zeroed();
-            if winuser::PeekMessageW(&mut msg, self.0.wnd.0, 0, 0, winuser::PM_REMOVE) != 0 {
-                winuser::TranslateMessage(&msg);
-                winuser::DispatchMessageW(&msg);
-                true
-            } else if wait {
-                winuser::WaitMessage();
-                false
-            } else { false }
-        }
-    }
-
-    pub (crate) fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
-        let z = self.0.cb_map.borrow_mut().remove(&cbid);
-        if let Some(cb) = z.as_ref() {
-            self.0.remove(cbid, cb);
-        };
-        z
-    }
-
-    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+impl<'a> crate::mainloop::Backend<'a> for Backend<'a> {
+    fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
         assert!(cbid.0 <= std::usize::MAX as u64);
         let cbu = cbid.0 as usize;
         let wnd = self.0.wnd.0;
+        if cb.multi_handles().is_some() {
+            return Err(MainLoopError::Unsupported);
+        }
         if let Some((socket, direction)) = cb.handle() {
-            let events = match direction {
-                IODirection::None => 0,
-                IODirection::Read => winsock2::FD_READ,
-                IODirection::Write => winsock2::FD_WRITE,
-                IODirection::Both => winsock2::FD_READ | winsock2::FD_WRITE,
-            } + winsock2::FD_CLOSE;
+            let events = dir_to_events(direction);
             let sock = socket.0 as usize;
             unsafe { winsock2::WSAAsyncSelect(sock, wnd, WM_SOCKET, events) };
             self.0.socket_map.borrow_mut().insert(sock, cbid);
         } else if let Some(d) = cb.duration_millis()? {
+            // SetTimer repeats at the given interval, so it is first armed for the time left
+            // until the deadline, and then re-armed with the period after the first expiry.
+            if cb.period_millis()?.is_some() { self.0.first_expiry.borrow_mut().insert(cbid); }
             unsafe { winuser::SetTimer(wnd, cbu, d, None); }
         } else {
             unsafe { winuser::PostMessageW(wnd, WM_CALL_ASAP, cbu, 0); }
@@ -213,4 +222,33 @@ impl<'a> Backend<'a> {
         self.0.cb_map.borrow_mut().insert(cbid, cb);
         Ok(())
     }
+
+    fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+        let z = self.0.cb_map.borrow_mut().remove(&cbid);
+        if let Some(cb) = z.as_ref() {
+            self.0.remove(cbid, cb);
+        };
+        z
+    }
+
+    fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+        let socket = self.0.cb_map.borrow().get(&cbid).and_then(|cb| cb.handle())
+            .ok_or_else(|| crate::mainloop::not_an_io_callback(cbid))?.0;
+        unsafe { winsock2::WSAAsyncSelect(socket.0 as usize, self.0.wnd.0, WM_SOCKET, dir_to_events(dir)) };
+        Ok(())
+    }
+
+    fn run_one(&self, wait: bool) -> bool {
+        unsafe {
+            let mut msg = mem::zeroed();
+            if winuser::PeekMessageW(&mut msg, self.0.wnd.0, 0, 0, winuser::PM_REMOVE) != 0 {
+                winuser::TranslateMessage(&msg);
+                winuser::DispatchMessageW(&msg);
+                true
+            } else if wait {
+                winuser::WaitMessage();
+                false
+            } else { false }
+        }
+    }
 }
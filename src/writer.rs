@@ -0,0 +1,201 @@
+//! Buffered writing to non-blocking I/O objects.
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::rc::Rc;
+use crate::{CbHandle, CbId, IOAble, IODirection, IOEvent};
+
+#[derive(Default)]
+struct State {
+    buf: RefCell<Vec<u8>>,
+    id: Cell<Option<CbId>>,
+    closed: Cell<bool>,
+}
+
+/// Writes queued data to a non-blocking I/O object, such as a TcpStream.
+///
+/// The writer waits for the object to become writable only while there is data
+/// pending. Partial writes and WouldBlock are handled by waiting and retrying.
+/// Use a `WriteQueue`, obtained from `queue`, to add data to write.
+///
+/// The callback is called with Ok(()) every time all queued data has been written,
+/// and with the error if writing fails, after which the writer is removed from the main loop.
+pub struct IOWriter<IO, F: FnMut(&mut IO, io::Result<()>)> {
+    io: IO,
+    f: F,
+    state: Rc<State>,
+}
+
+/// Queues data for an IOWriter.
+///
+/// This handle can be cloned and used from any callback on the main loop's thread.
+#[derive(Clone)]
+pub struct WriteQueue(Rc<State>);
+
+impl WriteQueue {
+    /// Queues data to be written.
+    ///
+    /// Fails with BrokenPipe if the writer has been dropped or failed.
+    pub fn write(&self, data: &[u8]) -> io::Result<()> {
+        if self.0.closed.get() { return Err(io::ErrorKind::BrokenPipe.into()) }
+        let mut buf = self.0.buf.borrow_mut();
+        let was_empty = buf.is_empty();
+        buf.extend_from_slice(data);
+        if was_empty && !buf.is_empty() {
+            if let Some(id) = self.0.id.get() {
+                crate::set_io_direction(id, IODirection::Write)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of bytes not yet written.
+    pub fn len(&self) -> usize { self.0.buf.borrow().len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl<IO: Write, F: FnMut(&mut IO, io::Result<()>)> IOWriter<IO, F> {
+    /// The I/O object must be in non-blocking mode.
+    pub fn new(io: IO, f: F) -> Self {
+        IOWriter { io: io, f: f, state: Default::default() }
+    }
+
+    pub fn queue(&self) -> WriteQueue { WriteQueue(self.state.clone()) }
+
+    // Writes as much as possible. Returns true if everything was written.
+    fn flush_buf(&mut self) -> io::Result<bool> {
+        let mut buf = self.state.buf.borrow_mut();
+        while !buf.is_empty() {
+            match self.io.write(&buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => { buf.drain(..n); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn pending_direction(&self) -> IODirection {
+        if self.state.buf.borrow().is_empty() { IODirection::None } else { IODirection::Write }
+    }
+
+    fn on_event(&mut self, ev: IOEvent) -> bool {
+        let r = if !self.state.buf.borrow().is_empty() {
+            self.flush_buf()
+        } else if ev.hangup || ev.error {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))
+        } else {
+            // Nothing to write
+            return true;
+        };
+        match r {
+            Ok(false) => true,
+            Ok(true) => {
+                if let Some(id) = self.state.id.get() { let _ = crate::set_io_direction(id, IODirection::None); }
+                (self.f)(&mut self.io, Ok(()));
+                true
+            }
+            Err(e) => {
+                self.state.closed.set(true);
+                (self.f)(&mut self.io, Err(e));
+                false
+            }
+        }
+    }
+}
+
+impl<IO, F: FnMut(&mut IO, io::Result<()>)> Drop for IOWriter<IO, F> {
+    fn drop(&mut self) {
+        self.state.closed.set(true);
+        self.state.id.set(None);
+    }
+}
+
+#[cfg(unix)]
+impl<IO, F> IOAble for IOWriter<IO, F>
+where IO: Write + std::os::unix::io::AsRawFd,
+      F: FnMut(&mut IO, io::Result<()>)
+{
+    fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_fd()) }
+    fn direction(&self) -> IODirection { self.pending_direction() }
+    fn registered(&mut self, id: CbId) { self.state.id.set(Some(id)) }
+    fn on_rw(&mut self, ev: IOEvent) -> bool { self.on_event(ev) }
+}
+
+#[cfg(windows)]
+impl<IO, F> IOAble for IOWriter<IO, F>
+where IO: Write + std::os::windows::io::AsRawSocket,
+      F: FnMut(&mut IO, io::Result<()>)
+{
+    fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_socket()) }
+    fn direction(&self) -> IODirection { self.pending_direction() }
+    fn registered(&mut self, id: CbId) { self.state.id.set(Some(id)) }
+    fn on_rw(&mut self, ev: IOEvent) -> bool { self.on_event(ev) }
+}
+
+#[cfg(unix)]
+#[test]
+fn write_queue() {
+    use std::os::unix::net::UnixStream;
+    use std::io::Read;
+    use crate::{MainLoop, IOReader};
+
+    let (a, b) = UnixStream::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    b.set_nonblocking(true).unwrap();
+    let data: Vec<u8> = (0..1_000_000).map(|x| x as u8).collect();
+    let mut received = vec!();
+    let done = Cell::new(0);
+    {
+        let w = IOWriter::new(a, |_: &mut UnixStream, r: io::Result<()>| {
+            r.unwrap();
+            done.set(done.get() + 1);
+        });
+        let q = w.queue();
+        // More than fits in the socket buffer, so there will be partial writes
+        q.write(&data[..600_000]).unwrap();
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_io(w).unwrap();
+        ml.call_io(IOReader { io: b, f: |io: &mut UnixStream, _| {
+            let mut buf = [0u8; 65536];
+            while let Ok(n) = io.read(&mut buf) {
+                if n == 0 { break; }
+                received.extend_from_slice(&buf[..n]);
+                if received.len() == 600_000 { q.write(&data[600_000..]).unwrap(); }
+                if received.len() == data.len() { crate::terminate(); }
+            }
+        }}).unwrap();
+        ml.run();
+    }
+    assert!(received == data);
+    assert_eq!(done.get(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn write_error() {
+    use std::os::unix::net::UnixStream;
+    use crate::MainLoop;
+
+    let (a, b) = UnixStream::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    drop(b);
+    let mut err = None;
+    let q;
+    {
+        let mut ml = MainLoop::new().unwrap();
+        let w = IOWriter::new(a, |_: &mut UnixStream, r: io::Result<()>| {
+            err = r.err();
+            crate::terminate();
+        });
+        q = w.queue();
+        ml.call_io(w).unwrap();
+        q.write(b"Hello").unwrap();
+        ml.run();
+    }
+    assert!(err.is_some());
+    assert_eq!(q.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+}
@@ -0,0 +1,22 @@
+//! Daylight saving time changes need a time zone of their own, and the time zone is
+//! process-wide, so this runs in a test binary of its own.
+#![cfg(all(unix, not(feature = "web")))]
+
+use std::time::{Duration, UNIX_EPOCH};
+use thin_main_loop::calendar::{Cron, Schedule};
+
+extern "C" { fn tzset(); }
+
+#[test]
+fn cron_dst_end() {
+    // America/New_York, without depending on the tz database
+    std::env::set_var("TZ", "EST5EDT,M3.2.0,M11.1.0");
+    unsafe { tzset() };
+    // 01:00 to 02:00 occurs twice when daylight saving time ends.
+    // 2026-11-01 01:15 EST, the second 01:15 that day
+    let t = UNIX_EPOCH + Duration::from_secs(1793513700);
+    let c = Cron::new("*/15 * * * *").unwrap();
+    let n = c.next_after(t).unwrap();
+    assert_eq!(n, t + Duration::from_secs(15 * 60));
+    assert_eq!(c.next_after(n).unwrap(), t + Duration::from_secs(30 * 60));
+}